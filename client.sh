@@ -57,8 +57,8 @@ auth_response=$(make_request "POST" "/user/auth" \
     "{\"username\":\"$USERNAME\",\"password\":\"$PASSWORD\"}" \
     "Authenticate user")
 
-# Simple handle extraction - just get the number
-HANDLE=$(echo "$auth_response" | grep -o '"handle":[0-9]*' | grep -o '[0-9]*' | head -1)
+# Simple handle extraction - just get the string
+HANDLE=$(echo "$auth_response" | grep -o '"handle":"[^"]*"' | cut -d'"' -f4 | head -1)
 if [ -n "$HANDLE" ]; then
     echo -e "Extracted handle: $HANDLE"
 fi
@@ -77,8 +77,8 @@ create_handle_response=$(make_request "POST" "/user/handle/create" \
     "{\"username\":\"$USERNAME\",\"password\":\"$PASSWORD\"}" \
     "Create a new handle for user")
 
-# Simple handle extraction - just get the number
-NEW_HANDLE=$(echo "$create_handle_response" | grep -o '"handle":[0-9]*' | grep -o '[0-9]*' | head -1)
+# Simple handle extraction - just get the string
+NEW_HANDLE=$(echo "$create_handle_response" | grep -o '"handle":"[^"]*"' | cut -d'"' -f4 | head -1)
 if [ -n "$NEW_HANDLE" ]; then
     echo -e "New handle created: $NEW_HANDLE"
 fi
@@ -92,7 +92,7 @@ print_header "Delete Handle"
 DELETE_HANDLE=${NEW_HANDLE:-$HANDLE}
 if [ -n "$DELETE_HANDLE" ]; then
     make_request "POST" "/user/handle/delete" \
-        "{\"username\":\"$USERNAME\",\"password\":\"$PASSWORD\",\"handle\":$DELETE_HANDLE}" \
+        "{\"username\":\"$USERNAME\",\"password\":\"$PASSWORD\",\"handle\":\"$DELETE_HANDLE\"}" \
         "Delete handle $DELETE_HANDLE"
 else
     echo -e "${RED}Cannot test handle deletion - no valid handle available${NC}"
@@ -114,7 +114,7 @@ make_request "POST" "/user/handle/create" \
 print_header "Handle Deletion with Invalid Credentials"
 if [ -n "$DELETE_HANDLE" ]; then
     make_request "POST" "/user/handle/delete" \
-        "{\"username\":\"$USERNAME\",\"password\":\"wrong_password\",\"handle\":$DELETE_HANDLE}" \
+        "{\"username\":\"$USERNAME\",\"password\":\"wrong_password\",\"handle\":\"$DELETE_HANDLE\"}" \
         "Attempt to delete handle with wrong password"
 fi
 
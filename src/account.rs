@@ -1,5 +1,7 @@
 use chrono::{DateTime, Utc};
 
+use crate::mfa::Factors;
+
 
 
 pub type UserID = u64;
@@ -12,7 +14,9 @@ pub struct Account {
     // Donator role
     premium: bool,
     random: i64,
-    // factors: Factors,
+    // Never sent to clients; holds the factor secrets
+    #[serde(skip)]
+    factors: Factors,
 }
 
 impl Account {
@@ -22,6 +26,7 @@ impl Account {
         creation_time: DateTime<Utc>,
         premium: bool,
         random: i64,
+        factors: Factors,
     ) -> Self {
         Self {
             username,
@@ -29,6 +34,7 @@ impl Account {
             creation_time,
             premium,
             random,
+            factors,
         }
     }
 
@@ -40,6 +46,10 @@ impl Account {
         self.creation_time
     }
 
+    pub fn factors(&self) -> &Factors {
+        &self.factors
+    }
+
     pub fn id(&self) -> UserID {
         self.user_id
     }
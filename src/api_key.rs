@@ -0,0 +1,91 @@
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+
+use crate::scope::Scope;
+
+/// Row ID of an API key
+pub type ApiKeyID = i64;
+
+const TOKEN_PREFIX: &str = "ablk_";
+const TOKEN_BYTES: usize = 32;
+
+/// A long-lived bearer token for scripts, CI and bots, so they don't need
+/// the user's password. Like handles, only a keyed hash of it is stored.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiKey {
+    id: ApiKeyID,
+    description: String,
+    scopes: Vec<Scope>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn from_db(
+        id: ApiKeyID,
+        description: String,
+        scopes: Vec<Scope>,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        last_used_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        ApiKey {
+            id,
+            description,
+            scopes,
+            created_at,
+            expires_at,
+            last_used_at,
+        }
+    }
+
+    pub fn id(&self) -> ApiKeyID {
+        self.id
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, Base64UrlUnpadded::encode_string(&bytes))
+}
+
+/// API keys are told apart from handles by their prefix
+pub fn is_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_told_apart_by_prefix() {
+        let token = generate_token();
+        assert!(is_token(&token));
+        assert_ne!(token, generate_token());
+        assert!(!is_token("abl_AAAA"));
+    }
+}
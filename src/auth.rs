@@ -0,0 +1,386 @@
+use std::fmt::Display;
+
+use base64ct::{Base64, Encoding};
+use chrono::Utc;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+use crate::{
+    account::Account,
+    api_key::{self, ApiKey},
+    config::{Config, SessionConfig},
+    database::Database,
+    handle::{Handle, HandleToken},
+    scope::Scope,
+};
+
+/// The account behind an `Authorization: Bearer <handle>` header, or an
+/// `Authorization: Bearer <API key>` one.
+///
+/// Take it as `Result<AuthenticatedUser, AuthError>` to fall back to other
+/// credentials when the header is missing. API keys and handles issued to
+/// OAuth clients can only do what their scopes allow, so routes must call
+/// `require_scope`, or `require_handle` if no scope covers them.
+pub struct AuthenticatedUser {
+    account: Account,
+    credential: Credential,
+}
+
+/// What the bearer token turned out to be
+pub enum Credential {
+    Handle(Handle),
+    ApiKey(ApiKey),
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No `Authorization` header was sent
+    Missing,
+    /// The header isn't `Bearer <handle>`
+    Malformed,
+    /// The handle doesn't exist or has expired
+    InvalidHandle,
+    /// The route needs the user to have entered their password (and a code,
+    /// with MFA) recently, see `RecentlyAuthenticatedUser`
+    StepUpRequired,
+    /// An API key or a handle limited to scopes was sent to a route that
+    /// needs a full handle
+    HandleRequired,
+    /// The API key or handle sent doesn't have the scope the route needs
+    InsufficientScope(Scope),
+}
+
+impl std::error::Error for AuthError {}
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => {
+                write!(f, "Missing Authorization header")
+            }
+            AuthError::Malformed => {
+                write!(f, "Authorization header must be 'Bearer <handle>'")
+            }
+            AuthError::InvalidHandle => {
+                write!(f, "Invalid or expired handle")
+            }
+            AuthError::StepUpRequired => {
+                write!(f, "Recent authentication required")
+            }
+            AuthError::HandleRequired => {
+                write!(f, "API keys and scoped handles can't be used here")
+            }
+            AuthError::InsufficientScope(scope) => {
+                write!(f, "Token is missing the '{}' scope", scope)
+            }
+        }
+    }
+}
+
+impl AuthenticatedUser {
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    pub fn credential(&self) -> &Credential {
+        &self.credential
+    }
+
+    /// The handle that was sent, or `None` for an API key
+    pub fn handle(&self) -> Option<&Handle> {
+        match &self.credential {
+            Credential::Handle(handle) => Some(handle),
+            Credential::ApiKey(_) => None,
+        }
+    }
+
+    pub fn handle_mut(&mut self) -> Option<&mut Handle> {
+        match &mut self.credential {
+            Credential::Handle(handle) => Some(handle),
+            Credential::ApiKey(_) => None,
+        }
+    }
+
+    pub fn into_account(self) -> Account {
+        self.account
+    }
+
+    /// What the credential is limited to, or `None` for a full handle
+    pub fn scopes(&self) -> Option<&[Scope]> {
+        match &self.credential {
+            Credential::Handle(handle) => handle.scopes(),
+            Credential::ApiKey(key) => Some(key.scopes()),
+        }
+    }
+
+    /// Full handles have every scope; API keys and scoped handles only the
+    /// ones they were created with
+    pub fn require_scope(self, scope: Scope) -> Result<Self, AuthError> {
+        let allowed = match &self.credential {
+            Credential::Handle(handle) => handle.has_scope(scope),
+            Credential::ApiKey(key) => key.has_scope(scope),
+        };
+        if allowed {
+            Ok(self)
+        } else {
+            Err(AuthError::InsufficientScope(scope))
+        }
+    }
+
+    /// Whether the user entered their password for the handle within the
+    /// step-up TTL. Never true for API keys.
+    pub fn password_is_recent(&self, session: &SessionConfig) -> bool {
+        let cutoff = Utc::now() - session.step_up_ttl();
+        self.handle().is_some_and(|handle| handle.authenticated_at() > cutoff)
+    }
+
+    /// Whether the user entered a code from a second factor for the handle
+    /// within the step-up TTL, or has no second factor. Never true for API
+    /// keys.
+    pub fn mfa_is_recent(&self, session: &SessionConfig) -> bool {
+        let cutoff = Utc::now() - session.step_up_ttl();
+        self.handle().is_some_and(|handle| {
+            !self.account.factors().any_enabled() || handle.mfa_at().is_some_and(|mfa_at| mfa_at > cutoff)
+        })
+    }
+
+    /// For routes no scope covers, such as managing the account's
+    /// credentials. Only handles that aren't limited to scopes get through.
+    pub fn require_handle(self) -> Result<Self, AuthError> {
+        match &self.credential {
+            Credential::Handle(handle) if handle.scopes().is_none() => Ok(self),
+            _ => Err(AuthError::HandleRequired),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(header) = request.headers().get_one("Authorization") else {
+            return Outcome::Error((Status::Unauthorized, AuthError::Missing));
+        };
+        let Some(value) = header.strip_prefix("Bearer ").map(str::trim) else {
+            return Outcome::Error((Status::Unauthorized, AuthError::Malformed));
+        };
+
+        let Outcome::Success(db) = request.guard::<Database>().await else {
+            unreachable!("the Database guard is infallible")
+        };
+        let found = if api_key::is_token(value) {
+            db.get_api_key(value).and_then(|(key, user_id)| {
+                let account = db.get_user_by_id(user_id)?;
+                db.touch_api_key(key.id())?;
+                Ok(AuthenticatedUser {
+                    account,
+                    credential: Credential::ApiKey(key),
+                })
+            })
+        } else {
+            let Some(token) = HandleToken::parse(value) else {
+                return Outcome::Error((Status::Unauthorized, AuthError::Malformed));
+            };
+            Handle::find(&token, &db).and_then(|mut handle| {
+                let account = handle.owner(&db)?;
+                handle.touch(&db)?;
+                Ok(AuthenticatedUser {
+                    account,
+                    credential: Credential::Handle(handle),
+                })
+            })
+        };
+        match found {
+            Ok(user) => Outcome::Success(user),
+            Err(e) => {
+                if !matches!(e, rusqlite::Error::QueryReturnedNoRows) {
+                    log::error!("Failed to look up bearer token: {}", e);
+                }
+                Outcome::Error((Status::Unauthorized, AuthError::InvalidHandle))
+            }
+        }
+    }
+}
+
+/// A client's ID and secret from an `Authorization: Basic` header (RFC 6749
+/// section 2.3.1). Only parsed here; checking them is up to the route.
+///
+/// Take it as `Result<ClientCredentials, AuthError>` to fall back to
+/// credentials in the body.
+pub struct ClientCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientCredentials {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(header) = request.headers().get_one("Authorization") else {
+            return Outcome::Error((Status::Unauthorized, AuthError::Missing));
+        };
+        let credentials = header
+            .strip_prefix("Basic ")
+            .and_then(|encoded| Base64::decode_vec(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (client_id, client_secret) = decoded.split_once(':')?;
+                // Both are form-encoded before being joined
+                Some(ClientCredentials {
+                    client_id: percent_decode(client_id)?,
+                    client_secret: percent_decode(client_secret)?,
+                })
+            });
+        match credentials {
+            Some(credentials) => Outcome::Success(credentials),
+            None => Outcome::Error((Status::Unauthorized, AuthError::Malformed)),
+        }
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    url::form_urlencoded::parse(format!("v={}", value).as_bytes())
+        .next()
+        .map(|(_, value)| value.into_owned())
+}
+
+/// An `AuthenticatedUser` whose handle was authenticated within the
+/// configured `step_up_ttl`: with their password, and with a second factor
+/// if they have one. Guards routes where a long-lived handle alone shouldn't
+/// be enough. Otherwise fails with `AuthError::StepUpRequired`, and the
+/// client should send the user through `/user/auth/step-up`. API keys and
+/// scoped handles never count as recent.
+pub struct RecentlyAuthenticatedUser(AuthenticatedUser);
+
+impl RecentlyAuthenticatedUser {
+    pub fn account(&self) -> &Account {
+        self.0.account()
+    }
+
+    pub fn handle(&self) -> &Handle {
+        self.0.handle().expect("only handles are recent")
+    }
+
+    pub fn into_inner(self) -> AuthenticatedUser {
+        self.0
+    }
+
+    pub fn into_account(self) -> Account {
+        self.0.into_account()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RecentlyAuthenticatedUser {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(user) => user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        let user = match user.require_handle() {
+            Ok(user) => user,
+            Err(e) => return Outcome::Error((Status::Unauthorized, e)),
+        };
+        let config = request.rocket().state::<Config>().cloned().unwrap_or_default();
+        if user.password_is_recent(&config.session) && user.mfa_is_recent(&config.session) {
+            Outcome::Success(RecentlyAuthenticatedUser(user))
+        } else {
+            Outcome::Error((Status::Unauthorized, AuthError::StepUpRequired))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::mfa::Factors;
+
+    fn user(credential: Credential) -> AuthenticatedUser {
+        let account = Account::new(
+            "alice".to_string(),
+            1,
+            Utc::now(),
+            false,
+            0,
+            Factors::default(),
+        );
+        AuthenticatedUser {
+            account,
+            credential,
+        }
+    }
+
+    fn handle(scopes: Option<Vec<Scope>>) -> Credential {
+        Credential::Handle(Handle::for_test(Utc::now(), scopes))
+    }
+
+    fn api_key(scopes: Vec<Scope>) -> Credential {
+        Credential::ApiKey(ApiKey::from_db(
+            1,
+            "ci".to_string(),
+            scopes,
+            Utc::now(),
+            None,
+            None,
+        ))
+    }
+
+    #[test]
+    fn handles_have_every_scope() {
+        for scope in Scope::ALL {
+            assert!(user(handle(None)).require_scope(*scope).is_ok());
+        }
+        assert!(user(handle(None)).require_handle().is_ok());
+    }
+
+    #[test]
+    fn api_keys_are_limited_to_their_scopes() {
+        assert!(user(api_key(vec![Scope::ProfileRead]))
+            .require_scope(Scope::ProfileRead)
+            .is_ok());
+        assert!(matches!(
+            user(api_key(vec![Scope::ProfileRead])).require_scope(Scope::HandlesWrite),
+            Err(AuthError::InsufficientScope(Scope::HandlesWrite))
+        ));
+        assert!(matches!(
+            user(api_key(Scope::ALL.to_vec())).require_handle(),
+            Err(AuthError::HandleRequired)
+        ));
+    }
+
+    #[test]
+    fn scoped_handles_are_limited_to_their_scopes() {
+        let scoped = || user(handle(Some(vec![Scope::ProfileRead])));
+        assert!(scoped().require_scope(Scope::ProfileRead).is_ok());
+        assert!(matches!(
+            scoped().require_scope(Scope::HandlesWrite),
+            Err(AuthError::InsufficientScope(Scope::HandlesWrite))
+        ));
+        assert!(matches!(
+            scoped().require_handle(),
+            Err(AuthError::HandleRequired)
+        ));
+        assert_eq!(scoped().scopes(), Some(&[Scope::ProfileRead][..]));
+    }
+
+    #[test]
+    fn only_handles_are_recent() {
+        let session = SessionConfig::default();
+        let recent = user(handle(None));
+        assert!(recent.password_is_recent(&session) && recent.mfa_is_recent(&session));
+
+        let stale = user(Credential::Handle(Handle::for_test(DateTime::UNIX_EPOCH, None)));
+        assert!(!stale.password_is_recent(&session));
+        assert!(!user(api_key(Scope::ALL.to_vec())).password_is_recent(&session));
+    }
+}
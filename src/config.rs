@@ -0,0 +1,316 @@
+use chrono::Duration;
+use rocket::figment::{self, Figment};
+
+/// Settings for the profile service, read from the `[default.abuelo]` table
+/// of `Rocket.toml` (or the `ROCKET_ABUELO` environment variable).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub database_path: String,
+    /// File holding the server's secret key, see `secret::server_secret`.
+    /// Keep it out of backups of the database.
+    pub secret_key_path: String,
+    pub password: PasswordConfig,
+    pub session: SessionConfig,
+    pub mfa: MfaConfig,
+    pub device_keys: DeviceKeyConfig,
+    pub device_grant: DeviceGrantConfig,
+    pub oidc: OidcConfig,
+}
+
+/// Which password hashers to use and their cost settings.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PasswordConfig {
+    /// Hasher used for new passwords and when upgrading old hashes
+    pub algorithm: String,
+    /// Other hashers that existing hashes may be verified with. Hashes from
+    /// these are replaced with `algorithm` on the next successful login.
+    pub accepted: Vec<String>,
+    /// Argon2id memory cost in KiB
+    pub memory_cost: u32,
+    /// Argon2id number of passes over the memory
+    pub time_cost: u32,
+    /// Argon2id degree of parallelism
+    pub parallelism: u32,
+    pub pbkdf2_iterations: u32,
+    pub bcrypt_cost: u32,
+}
+
+/// How long handles handed out by `/user/auth` stay valid.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Seconds a handle lasts after it is issued, however often it is used
+    pub absolute_ttl: i64,
+    /// Seconds a handle lasts without being used
+    pub idle_ttl: i64,
+    /// Handles a user may hold at once. Creating another evicts the least
+    /// recently used one. 0 means no limit.
+    pub max_handles_per_user: u32,
+    /// Seconds between runs of the background purge of expired handles
+    pub purge_interval: u64,
+    /// Seconds after entering their password (and a code, with MFA) that a
+    /// user may do sensitive things like deleting handles
+    pub step_up_ttl: i64,
+    /// Seconds a signed access token lasts. The handle it was issued with
+    /// is what gets it refreshed.
+    pub access_token_ttl: i64,
+    /// `aud` of access tokens: the services that accept them, which should
+    /// reject tokens meant for anyone else
+    pub access_token_audience: String,
+}
+
+/// Second factors users can enroll.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MfaConfig {
+    /// Name authenticator apps show next to the username
+    pub issuer: String,
+    /// 30 second steps a TOTP code is still accepted for before or after
+    /// its own, to allow for clocks being off
+    pub totp_skew: u8,
+    /// Codes ahead of the last accepted one that an HOTP token may be on,
+    /// e.g. from being pressed without logging in
+    pub hotp_look_ahead: u32,
+    /// Seconds a user has to enter a code after their password
+    pub challenge_ttl: i64,
+    /// Wrong codes allowed per challenge before it is revoked
+    pub challenge_attempts: u32,
+    /// Single-use codes handed out with the first factor, for getting in
+    /// without any of the factors
+    pub recovery_codes: u32,
+    /// Seconds a device stays trusted after the user opts in while logging
+    /// in with a code, so that logging in from it only needs the password
+    pub trusted_device_ttl: i64,
+}
+
+/// Public keys devices log in with instead of a password.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DeviceKeyConfig {
+    /// Seconds a device has to sign the nonce it asked for
+    pub nonce_ttl: i64,
+    /// Keys a user may register. 0 means no limit.
+    pub max_keys_per_user: u32,
+}
+
+/// The OAuth device authorization grant (RFC 8628), for tools that can't
+/// prompt for a password.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DeviceGrantConfig {
+    /// Page where users enter the code shown on the device
+    pub verification_uri: String,
+    /// Seconds the user has to approve a device
+    pub code_ttl: i64,
+    /// Seconds devices have to wait between polls
+    pub interval: i64,
+    /// `client_id`s tools may start the grant with. Users see it as the
+    /// name of the session, so it can't be left to the tool.
+    pub clients: Vec<String>,
+}
+
+/// Abuelo as an OpenID Connect provider for other services.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct OidcConfig {
+    /// Public base URL of this server, used as `iss` in ID tokens and for
+    /// the endpoints in the discovery document
+    pub issuer: String,
+    /// Page of the web frontend that logs the user in and asks them to
+    /// approve a client. `/oauth/authorize` redirects there with the
+    /// client's parameters.
+    pub login_page: String,
+    /// Seconds a client has to redeem an authorization code
+    pub code_ttl: i64,
+    /// Seconds ID tokens are valid for
+    pub id_token_ttl: i64,
+    /// Seconds a signing key is used before a new one replaces it
+    pub key_rotation: i64,
+    /// Seconds the next key is published before it starts signing, so
+    /// services that cache the JWKS already have it. Should be longer than
+    /// they cache it for.
+    pub key_publication: i64,
+    /// Seconds a replaced key is still published, so tokens it signed can
+    /// be checked. Should be longer than any token lasts.
+    pub key_retention: i64,
+    /// IDs of the confidential clients that may use `/introspect`. Any user
+    /// can register a client, so only the operator can make one a resource
+    /// server.
+    pub resource_servers: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database_path: "user_db.db3".to_string(),
+            secret_key_path: "abuelo.key".to_string(),
+            password: PasswordConfig::default(),
+            session: SessionConfig::default(),
+            mfa: MfaConfig::default(),
+            device_keys: DeviceKeyConfig::default(),
+            device_grant: DeviceGrantConfig::default(),
+            oidc: OidcConfig::default(),
+        }
+    }
+}
+
+impl Default for PasswordConfig {
+    // Costs follow OWASP's minimum recommendations
+    fn default() -> Self {
+        Self {
+            algorithm: "argon2id".to_string(),
+            accepted: vec!["abuelo-sha256".to_string()],
+            memory_cost: 19 * 1024,
+            time_cost: 2,
+            parallelism: 1,
+            pbkdf2_iterations: 600_000,
+            bcrypt_cost: 12,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            absolute_ttl: 30 * 24 * 60 * 60,
+            idle_ttl: 7 * 24 * 60 * 60,
+            max_handles_per_user: 20,
+            purge_interval: 60 * 60,
+            step_up_ttl: 10 * 60,
+            access_token_ttl: 5 * 60,
+            access_token_audience: "http://localhost:8000".to_string(),
+        }
+    }
+}
+
+impl Default for MfaConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "Abuelo".to_string(),
+            totp_skew: 1,
+            hotp_look_ahead: 10,
+            challenge_ttl: 5 * 60,
+            challenge_attempts: 5,
+            recovery_codes: 10,
+            trusted_device_ttl: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for DeviceKeyConfig {
+    fn default() -> Self {
+        Self {
+            nonce_ttl: 60,
+            max_keys_per_user: 20,
+        }
+    }
+}
+
+impl Default for DeviceGrantConfig {
+    fn default() -> Self {
+        Self {
+            verification_uri: "http://localhost:8000/device".to_string(),
+            code_ttl: 10 * 60,
+            interval: 5,
+            clients: Vec::new(),
+        }
+    }
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "http://localhost:8000".to_string(),
+            login_page: "http://localhost:8000/login".to_string(),
+            code_ttl: 60,
+            id_token_ttl: 60 * 60,
+            key_rotation: 30 * 24 * 60 * 60,
+            key_publication: 24 * 60 * 60,
+            key_retention: 7 * 24 * 60 * 60,
+            resource_servers: Vec::new(),
+        }
+    }
+}
+
+impl MfaConfig {
+    pub fn challenge_ttl(&self) -> Duration {
+        Duration::seconds(self.challenge_ttl)
+    }
+
+    pub fn trusted_device_ttl(&self) -> Duration {
+        Duration::seconds(self.trusted_device_ttl)
+    }
+}
+
+impl DeviceKeyConfig {
+    pub fn nonce_ttl(&self) -> Duration {
+        Duration::seconds(self.nonce_ttl)
+    }
+}
+
+impl DeviceGrantConfig {
+    pub fn code_ttl(&self) -> Duration {
+        Duration::seconds(self.code_ttl)
+    }
+
+    pub fn allows_client(&self, client_id: &str) -> bool {
+        self.clients.iter().any(|client| client == client_id)
+    }
+}
+
+impl OidcConfig {
+    pub fn code_ttl(&self) -> Duration {
+        Duration::seconds(self.code_ttl)
+    }
+
+    pub fn id_token_ttl(&self) -> Duration {
+        Duration::seconds(self.id_token_ttl)
+    }
+
+    pub fn key_rotation(&self) -> Duration {
+        Duration::seconds(self.key_rotation)
+    }
+
+    pub fn key_publication(&self) -> Duration {
+        Duration::seconds(self.key_publication)
+    }
+
+    pub fn key_retention(&self) -> Duration {
+        Duration::seconds(self.key_retention)
+    }
+
+    pub fn is_resource_server(&self, client_id: &str) -> bool {
+        self.resource_servers.iter().any(|client| client == client_id)
+    }
+}
+
+impl SessionConfig {
+    pub fn absolute_ttl(&self) -> Duration {
+        Duration::seconds(self.absolute_ttl)
+    }
+
+    pub fn idle_ttl(&self) -> Duration {
+        Duration::seconds(self.idle_ttl)
+    }
+
+    pub fn step_up_ttl(&self) -> Duration {
+        Duration::seconds(self.step_up_ttl)
+    }
+
+    pub fn access_token_ttl(&self) -> Duration {
+        Duration::seconds(self.access_token_ttl)
+    }
+}
+
+impl Config {
+    /// Fails if the `abuelo` table is there but can't be read, rather than
+    /// running with the defaults in place of e.g. the secret key path
+    pub fn from_figment(figment: &Figment) -> Result<Self, figment::Error> {
+        if !figment.contains("abuelo") {
+            return Ok(Config::default());
+        }
+        figment.extract_inner::<Config>("abuelo")
+    }
+}
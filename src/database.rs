@@ -1,13 +1,82 @@
 use std::{fmt::Display, rc::Rc};
 
+use base64ct::{Base64Unpadded, Encoding};
 use chrono::{DateTime, Utc};
-use rusqlite::{Connection, Result};
-use sha2::{Digest, Sha256};
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+use ring::{aead, hmac};
+use rusqlite::{named_params, Connection, OptionalExtension, Result, Transaction, TransactionBehavior};
 
-use crate::account::{Account, UserID};
+use crate::{
+    account::{Account, UserID},
+    api_key::{self, ApiKey, ApiKeyID},
+    config::{Config, DeviceGrantConfig, DeviceKeyConfig, MfaConfig, OidcConfig, SessionConfig},
+    device_key::{self, DeviceKey, DeviceKeyID},
+    handle::{ClientInfo, Handle, HandleID, HandleToken},
+    metrics,
+    mfa::{self, Factor, FactorID, FactorKind, Factors},
+    jwt::{self, Jwk, SigningKey},
+    oauth::{self, AuthorizationGrant, DeviceGrantPoll, OAuthClient},
+    password::{self, Hashers, LegacySha256Hasher, PasswordCheck},
+    scope::{self, Scope},
+    secret,
+    trusted_device::{self, TrustedDevice, TrustedDeviceID, TrustedDeviceSecret},
+};
 
 pub struct Database {
     conn: Connection,
+    hashers: Hashers,
+    session: SessionConfig,
+    handle_key: hmac::Key,
+    mfa: MfaConfig,
+    factor_key: aead::LessSafeKey,
+    challenge_key: hmac::Key,
+    recovery_code_key: hmac::Key,
+    trusted_device_key: hmac::Key,
+    device_keys: DeviceKeyConfig,
+    device_grant: DeviceGrantConfig,
+    device_code_key: hmac::Key,
+    oidc: OidcConfig,
+    client_secret_key: hmac::Key,
+    authorization_code_key: hmac::Key,
+    signing_key_key: aead::LessSafeKey,
+    api_key_key: hmac::Key,
+}
+
+/// Condition for a handle that is still valid, see `Database::handle_cutoffs`
+const HANDLE_ACTIVE: &str = "handle.expires_at > :now AND handle.last_used_at > :idle_cutoff";
+
+/// Schema and data changes for databases created by older versions, in
+/// order. `PRAGMA user_version` records how many have been applied. The
+/// `CREATE TABLE` statements in `open` describe the original schema, so new
+/// columns are only ever added here.
+const MIGRATIONS: &[fn(&Database) -> Result<()>] = &[
+    Database::tag_legacy_password_hashes,
+    Database::add_salt_column,
+    Database::add_handle_timestamps,
+    Database::hash_stored_handles,
+    Database::add_totp_table,
+    Database::add_mfa_challenge_table,
+    Database::add_recovery_code_table,
+    Database::add_totp_last_step,
+    Database::add_factor_table,
+    Database::add_handle_authentication_times,
+    Database::add_trusted_device_table,
+    Database::add_device_key_tables,
+    Database::add_device_grant_table,
+    Database::add_oidc_tables,
+    Database::add_handle_scopes,
+    Database::add_api_key_table,
+    Database::add_handle_client_info,
+];
+
+/// Rows removed by `Database::purge_handles`
+#[derive(Debug, Default)]
+pub struct PurgeStats {
+    pub expired: usize,
+    pub orphaned: usize,
 }
 
 #[derive(Debug)]
@@ -22,6 +91,20 @@ pub enum HandleDBError {
     DBError(rusqlite::Error),
 }
 
+#[derive(Debug)]
+pub enum FactorDBError {
+    NameTaken,
+    DBError(rusqlite::Error),
+}
+
+#[derive(Debug)]
+pub enum DeviceKeyDBError {
+    NameTaken,
+    KeyTaken,
+    TooManyKeys,
+    DBError(rusqlite::Error),
+}
+
 impl From<rusqlite::Error> for UserCreationError {
     fn from(value: rusqlite::Error) -> Self {
         Self::DBError(value)
@@ -33,6 +116,18 @@ impl From<rusqlite::Error> for HandleDBError {
         Self::DBError(value)
     }
 }
+
+impl From<rusqlite::Error> for FactorDBError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::DBError(value)
+    }
+}
+
+impl From<rusqlite::Error> for DeviceKeyDBError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::DBError(value)
+    }
+}
 impl std::error::Error for UserCreationError {}
 impl Display for UserCreationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -61,12 +156,71 @@ impl Display for HandleDBError {
     }
 }
 
+impl std::error::Error for FactorDBError {}
+impl Display for FactorDBError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FactorDBError::NameTaken => {
+                write!(f, "A factor with that name already exists.")
+            }
+            FactorDBError::DBError(e) => {
+                write!(f, "DBError: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceKeyDBError {}
+impl Display for DeviceKeyDBError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceKeyDBError::NameTaken => {
+                write!(f, "A device key with that name already exists.")
+            }
+            DeviceKeyDBError::KeyTaken => {
+                write!(f, "That public key is already registered.")
+            }
+            DeviceKeyDBError::TooManyKeys => {
+                write!(f, "Too many device keys.")
+            }
+            DeviceKeyDBError::DBError(e) => {
+                write!(f, "DBError: {}", e)
+            }
+        }
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Database {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<Config>().cloned().unwrap_or_default();
+        Outcome::Success(Database::with_config(config))
+    }
+}
+
 impl Database {
     pub fn new() -> Self {
-        Self::new_with_path("user_db.db3")
+        Self::with_config(Config::default())
     }
     
     pub fn new_with_path(db_path: &str) -> Self {
+        Self::open(db_path, Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        let db_path = config.database_path.clone();
+        Self::open(&db_path, config)
+    }
+
+    fn open(db_path: &str, config: Config) -> Self {
         let conn = Connection::open(db_path).unwrap();
 
         // If this returns an error; it's prolly cuz the table already exists.
@@ -96,7 +250,412 @@ impl Database {
             (),
         );
 
-        Self { conn }
+        Self {
+            conn,
+            hashers: Hashers::from_config(&config.password),
+            session: config.session.clone(),
+            handle_key: secret::derive_key(&config, "handle"),
+            mfa: config.mfa.clone(),
+            factor_key: secret::derive_sealing_key(&config, "factor"),
+            challenge_key: secret::derive_key(&config, "mfa-challenge"),
+            recovery_code_key: secret::derive_key(&config, "recovery-code"),
+            trusted_device_key: secret::derive_key(&config, "trusted-device"),
+            device_keys: config.device_keys.clone(),
+            device_grant: config.device_grant.clone(),
+            device_code_key: secret::derive_key(&config, "device-code"),
+            oidc: config.oidc.clone(),
+            client_secret_key: secret::derive_key(&config, "client-secret"),
+            authorization_code_key: secret::derive_key(&config, "authorization-code"),
+            signing_key_key: secret::derive_sealing_key(&config, "signing-key"),
+            api_key_key: secret::derive_key(&config, "api-key"),
+        }
+    }
+
+    /// Brings the schema up to date. Run once at startup, before any
+    /// requests are served. Each migration is applied together with its
+    /// `user_version` bump, so a failure leaves the database as it was
+    /// before that migration.
+    pub fn migrate(&self) -> Result<()> {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            // Taking the write lock before reading the version means a
+            // second process can't apply the same migration again
+            let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+            let version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            if version > i {
+                continue;
+            }
+            migration(self)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+            log::info!("Applied database migration {}", i + 1);
+        }
+        Ok(())
+    }
+
+    /// Hashes from before `PasswordHasher` were bare hex SHA-256 digests
+    /// salted with the creation minute and `random_value`. Rewrite them as
+    /// `$abuelo-sha256$` hashes that carry that salt with them.
+    fn tag_legacy_password_hashes(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, password_hash, creation_time, random_value FROM user
+            WHERE password_hash NOT LIKE '$%'",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let user_id: UserID = row.get(0)?;
+            let hash: String = row.get(1)?;
+            let creation_time: DateTime<Utc> = row.get(2)?;
+            let num: i64 = row.get(3)?;
+            Ok((user_id, hash, creation_time, num as u64))
+        })?;
+
+        for row in rows {
+            let (user_id, hash, creation_time, num) = row?;
+            let salt = format!("{}{}", creation_time.format("%Y-%m-%d-%H-%M"), num);
+            let Some(digest) = decode_hex(&hash) else {
+                log::error!("User {} has an unrecognized password hash", user_id);
+                continue;
+            };
+            self.conn.execute(
+                "UPDATE user SET password_hash=?1 WHERE user_id=?2",
+                (LegacySha256Hasher::encode(salt.as_bytes(), &digest), user_id),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Moves salts into their own column, copied out of the hash: legacy
+    /// rows keep the salt derived from their creation minute and
+    /// `random_value`, and imported PHC and bcrypt hashes keep their own.
+    /// Hashes with no readable salt are left NULL, which `check_login`
+    /// refuses.
+    fn add_salt_column(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE user ADD COLUMN salt BLOB", ())?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT user_id, password_hash FROM user WHERE salt IS NULL")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<usize, UserID>(0)?, row.get::<usize, String>(1)?))
+        })?;
+        for row in rows {
+            let (user_id, hash) = row?;
+            if let Some(salt) = password::embedded_salt(&hash) {
+                self.conn.execute(
+                    "UPDATE user SET salt=?1 WHERE user_id=?2",
+                    (salt, user_id),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles used to be valid forever. Existing ones get a full lifetime
+    /// starting from the upgrade rather than being cut off immediately.
+    fn add_handle_timestamps(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE handle ADD COLUMN created_at DATETIME", ())?;
+        self.conn.execute("ALTER TABLE handle ADD COLUMN expires_at DATETIME", ())?;
+        self.conn.execute("ALTER TABLE handle ADD COLUMN last_used_at DATETIME", ())?;
+
+        let now = Utc::now();
+        self.conn.execute(
+            "UPDATE handle SET created_at=?1, expires_at=?2, last_used_at=?1",
+            (now, now + self.session.absolute_ttl()),
+        )?;
+        Ok(())
+    }
+
+    /// Replaces plaintext handle values with their keyed hashes
+    fn hash_stored_handles(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE handle ADD COLUMN handle_hash TEXT", ())?;
+
+        let mut stmt = self.conn.prepare("SELECT handle_id, handle_val FROM handle")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<usize, HandleID>(0)?, row.get::<usize, u64>(1)?))
+        })?;
+        for row in rows {
+            let (handle_id, handle) = row?;
+            self.conn.execute(
+                "UPDATE handle SET handle_hash=?1 WHERE handle_id=?2",
+                (self.hash_handle(&HandleToken::from_legacy(handle)), handle_id),
+            )?;
+        }
+
+        self.conn.execute("ALTER TABLE handle DROP COLUMN handle_val", ())?;
+        self.conn.execute(
+            "CREATE UNIQUE INDEX handle_hash_index ON handle (handle_hash)",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// TOTP secrets, encrypted with a key derived from the server secret.
+    /// A row stays unconfirmed until the user proves their authenticator
+    /// produces matching codes, and only confirmed rows are enforced.
+    fn add_totp_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE totp (
+            user_id             INTEGER PRIMARY KEY,
+            secret              BLOB NOT NULL,
+            confirmed           BOOL NOT NULL,
+            created_at          DATETIME NOT NULL,
+            CONSTRAINT fk_usr_totp FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Pending second steps of logins, stored hashed like handles
+    fn add_mfa_challenge_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE mfa_challenge (
+            challenge_hash      TEXT PRIMARY KEY,
+            user_id             INTEGER NOT NULL,
+            expires_at          DATETIME NOT NULL,
+            attempts_left       INTEGER NOT NULL,
+            CONSTRAINT fk_usr_mfa_challenge FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Unused recovery codes, stored hashed like handles
+    fn add_recovery_code_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE recovery_code (
+            code_id             INTEGER PRIMARY KEY,
+            user_id             INTEGER NOT NULL,
+            code_hash           TEXT NOT NULL,
+            CONSTRAINT fk_usr_recovery_code FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE INDEX recovery_code_user_index ON recovery_code (user_id)",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// The last TOTP time step a code was accepted for, so codes can't be
+    /// replayed
+    fn add_totp_last_step(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE totp ADD COLUMN last_step INTEGER", ())?;
+        Ok(())
+    }
+
+    /// Generalizes `totp` to any number of named factors per user. Existing
+    /// TOTP secrets are moved over as is, sealed the same way.
+    fn add_factor_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE factor (
+            factor_id           INTEGER PRIMARY KEY,
+            user_id             INTEGER NOT NULL,
+            name                TEXT NOT NULL,
+            kind                TEXT NOT NULL,
+            secret              BLOB NOT NULL,
+            confirmed           BOOL NOT NULL,
+            created_at          DATETIME NOT NULL,
+            last_used_at        DATETIME,
+            counter             INTEGER,
+            CONSTRAINT fk_usr_factor FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE UNIQUE INDEX factor_name_index ON factor (user_id, name)",
+            (),
+        )?;
+        self.conn.execute(
+            "INSERT INTO factor (user_id, name, kind, secret, confirmed, created_at, counter)
+            SELECT user_id, 'authenticator', 'totp', secret, confirmed, created_at, last_step
+            FROM totp",
+            (),
+        )?;
+        self.conn.execute("DROP TABLE totp", ())?;
+        Ok(())
+    }
+
+    /// Existing handles were all issued by logging in with a password
+    fn add_handle_authentication_times(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE handle ADD COLUMN authenticated_at DATETIME", ())?;
+        self.conn.execute("ALTER TABLE handle ADD COLUMN mfa_at DATETIME", ())?;
+        self.conn.execute("UPDATE handle SET authenticated_at=created_at", ())?;
+        Ok(())
+    }
+
+    /// Devices that skip the second factor. Each token carries a random
+    /// secret, stored hashed like handles, that has to match its row, as
+    /// SQLite can reuse the ID of a deleted one.
+    fn add_trusted_device_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE trusted_device (
+            device_id           INTEGER PRIMARY KEY,
+            user_id             INTEGER NOT NULL,
+            name                TEXT,
+            secret_hash         TEXT NOT NULL,
+            created_at          DATETIME NOT NULL,
+            expires_at          DATETIME NOT NULL,
+            last_used_at        DATETIME,
+            CONSTRAINT fk_usr_trusted_device FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE INDEX trusted_device_user_index ON trusted_device (user_id)",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Public keys devices log in with, each registered once per user, and
+    /// the nonces handed out for them to sign. Nonces aren't secrets, so
+    /// unlike challenges they're stored as is.
+    fn add_device_key_tables(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE device_key (
+            key_id              INTEGER PRIMARY KEY,
+            user_id             INTEGER NOT NULL,
+            name                TEXT NOT NULL,
+            public_key          BLOB NOT NULL,
+            created_at          DATETIME NOT NULL,
+            last_used_at        DATETIME,
+            UNIQUE (user_id, public_key),
+            CONSTRAINT fk_usr_device_key FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE UNIQUE INDEX device_key_name_index ON device_key (user_id, name)",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE TABLE device_nonce (
+            nonce               TEXT PRIMARY KEY,
+            key_id              INTEGER NOT NULL,
+            expires_at          DATETIME NOT NULL,
+            CONSTRAINT fk_key_device_nonce FOREIGN KEY (key_id)
+            REFERENCES device_key (key_id)
+        )",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Pending OAuth device authorizations. `user_id` is set once a user
+    /// approves one; until then `status` is 'pending'.
+    fn add_device_grant_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE device_grant (
+            device_code_hash    TEXT PRIMARY KEY,
+            user_code           TEXT NOT NULL UNIQUE,
+            client_id           TEXT NOT NULL,
+            status              TEXT NOT NULL,
+            user_id             INTEGER,
+            expires_at          DATETIME NOT NULL,
+            interval            INTEGER NOT NULL,
+            last_polled_at      DATETIME,
+            CONSTRAINT fk_usr_device_grant FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Registered OAuth clients, the authorization codes issued to them, and
+    /// the keys ID tokens are signed with. Redirect URIs are a JSON array;
+    /// private keys are sealed like factor secrets.
+    fn add_oidc_tables(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE oauth_client (
+            client_id           TEXT PRIMARY KEY,
+            owner_id            INTEGER NOT NULL,
+            name                TEXT NOT NULL,
+            redirect_uris       TEXT NOT NULL,
+            secret_hash         TEXT,
+            created_at          DATETIME NOT NULL,
+            CONSTRAINT fk_usr_oauth_client FOREIGN KEY (owner_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE TABLE authorization_code (
+            code_hash           TEXT PRIMARY KEY,
+            client_id           TEXT NOT NULL,
+            user_id             INTEGER NOT NULL,
+            redirect_uri        TEXT NOT NULL,
+            scope               TEXT NOT NULL,
+            nonce               TEXT,
+            code_challenge      TEXT NOT NULL,
+            auth_time           DATETIME,
+            expires_at          DATETIME NOT NULL,
+            CONSTRAINT fk_client_authorization_code FOREIGN KEY (client_id)
+            REFERENCES oauth_client (client_id),
+            CONSTRAINT fk_usr_authorization_code FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        self.conn.execute(
+            "CREATE TABLE signing_key (
+            key_id              TEXT PRIMARY KEY,
+            private_key         BLOB NOT NULL,
+            public_key          BLOB NOT NULL,
+            created_at          DATETIME NOT NULL,
+            activates_at        DATETIME NOT NULL,
+            retired_at          DATETIME
+        )",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Handles issued to OAuth clients are limited to scopes, and revoked
+    /// along with the client. NULL scopes means a handle can do everything,
+    /// as all existing ones could.
+    fn add_handle_scopes(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE handle ADD COLUMN scopes TEXT", ())?;
+        self.conn.execute("ALTER TABLE handle ADD COLUMN client_id TEXT", ())?;
+        Ok(())
+    }
+
+    /// API keys, kept apart from handles since they don't go idle and are
+    /// limited to their scopes. Scopes are space-separated.
+    fn add_api_key_table(&self) -> Result<()> {
+        self.conn.execute(
+            "CREATE TABLE api_key (
+            key_id              INTEGER PRIMARY KEY,
+            user_id             INTEGER NOT NULL,
+            key_hash            TEXT NOT NULL UNIQUE,
+            description         TEXT NOT NULL,
+            scopes              TEXT NOT NULL,
+            created_at          DATETIME NOT NULL,
+            expires_at          DATETIME,
+            last_used_at        DATETIME,
+            CONSTRAINT fk_usr_api_key FOREIGN KEY (user_id)
+            REFERENCES user (user_id)
+        )",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Where each handle was created from, shown when listing sessions.
+    /// Older handles have none of it.
+    fn add_handle_client_info(&self) -> Result<()> {
+        self.conn.execute("ALTER TABLE handle ADD COLUMN user_agent TEXT", ())?;
+        self.conn.execute("ALTER TABLE handle ADD COLUMN ip_address TEXT", ())?;
+        self.conn.execute("ALTER TABLE handle ADD COLUMN device_name TEXT", ())?;
+        Ok(())
     }
 
     pub fn get_user(&self, username: &str) -> Result<Account> {
@@ -112,124 +671,1622 @@ impl Database {
                     Ok((user_id, creation_time, premium, random))
                 },
             )?;
+        let factors = self.get_factors(user_id)?;
         Ok(Account::new(
             username.to_string(),
             user_id,
             creation_time,
             premium,
             random,
+            factors,
         ))
     }
 
+    pub fn get_user_by_id(&self, user_id: UserID) -> Result<Account> {
+        let username: String = self.conn.query_row(
+            "SELECT username FROM user WHERE user_id=?1",
+            [user_id],
+            |row| row.get(0),
+        )?;
+        self.get_user(&username)
+    }
+
     pub fn add_user(&self, username: &str, password: &str) -> Result<(), UserCreationError> {
         if self.get_user(username).is_ok() {
             return Err(UserCreationError::UsernameTaken);
         }
         let creation_time = Utc::now();
         let num = rand::random::<i64>(); // Changed to i64 to match schema
-        let password_hash = self.hash_password(password, creation_time, num as u64);
+        let salt = password::generate_salt();
+        let password_hash = self.hashers.hash(password, &salt);
         self.conn.execute(
             "INSERT INTO user (
             username, 
             password_hash, 
+            salt,
             creation_time, 
             is_premium,
             random_value) 
-            VALUES (?1, ?2, ?3, ?4, ?5)",
-            (username, password_hash, creation_time, false, num),
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (username, password_hash, salt, creation_time, false, num),
         )?;
         Ok(())
     }
 
-    fn hash_password(&self, password: &str, creation_time: DateTime<Utc>, num: u64) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(password);
-        hasher.update(creation_time.format("%Y-%m-%d-%H-%M").to_string());
-        hasher.update(num.to_string());
-        format!("{:x}", hasher.finalize())
+    // Hash the password with a fresh salt and the current hasher, replacing
+    // whatever was stored
+    pub fn set_password_hash(&self, username: &str, password: &str) -> Result<()> {
+        let salt = password::generate_salt();
+        self.conn.execute(
+            "UPDATE user SET password_hash=?1, salt=?2 WHERE username=?3",
+            (self.hashers.hash(password, &salt), salt, username),
+        )?;
+        Ok(())
     }
 
     pub fn check_login(&self, username: &str, password: &str) -> bool {
-        let result = self.conn.query_row(
-            "SELECT creation_time, random_value FROM user WHERE username=?1",
+        let saved: Result<(Rc<str>, Option<Vec<u8>>)> = self.conn.query_row(
+            "SELECT password_hash, salt FROM user WHERE username=?1",
             [username],
-            |row| {
-                let creation_time = row.get(0)?;
-                let num: i64 = row.get(1)?;
-                Ok((creation_time, num as u64))
-            },
+            |row| Ok((row.get(0)?, row.get(1)?)),
         );
-        if result.is_err() {
-            return false;
+
+        // Unknown usernames still cost a full hash verification, so the
+        // response time doesn't reveal whether the account exists
+        let check = match saved {
+            Ok((saved_password_hash, salt)) => {
+                match salt.or_else(|| password::embedded_salt(&saved_password_hash)) {
+                    Some(salt) => self.hashers.verify(password, &salt, &saved_password_hash),
+                    None => {
+                        log::error!("Password hash of user {} has no salt", username);
+                        self.hashers.verify_missing(password)
+                    }
+                }
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => self.hashers.verify_missing(password),
+            Err(e) => {
+                log::error!("Failed to look up password hash for user {}: {}", username, e);
+                self.hashers.verify_missing(password)
+            }
+        };
+        match check {
+            PasswordCheck::Invalid => false,
+            PasswordCheck::Valid => true,
+            PasswordCheck::Outdated => {
+                // Upgrade the stored hash while we have the plaintext password
+                match self.set_password_hash(username, password) {
+                    Ok(()) => log::info!("Upgraded password hash for user: {}", username),
+                    Err(e) => log::error!("Failed to upgrade password hash for user {}: {}", username, e),
+                }
+                true
+            }
         }
-        let (creation_time, num) = result.unwrap();
-        let saved_password_hash: Result<Rc<str>> = self.conn.query_row(
-            "SELECT password_hash FROM user WHERE username=?1",
-            [username],
-            |row| row.get::<usize, Rc<str>>(0),
-        );
-        if saved_password_hash.is_err() {
-            return false;
+    }
+
+    // FACTOR FUNCTIONS --------------------------------------------------
+    pub fn mfa_config(&self) -> &MfaConfig {
+        &self.mfa
+    }
+
+    // The user's confirmed factors and how many recovery codes they have
+    fn get_factors(&self, user_id: UserID) -> Result<Factors> {
+        let factors = self
+            .get_all_factors(user_id)?
+            .into_iter()
+            .filter(Factor::is_confirmed)
+            .collect();
+        Ok(Factors::new(factors, self.count_recovery_codes(user_id)?))
+    }
+
+    // The AAD ties each sealed secret to its user, so rows can't be swapped
+    fn factor_aad(kind: FactorKind, user_id: UserID) -> Vec<u8> {
+        format!("{}:{}", kind.as_str(), user_id).into_bytes()
+    }
+
+    fn factor_from_row(&self, user_id: UserID, row: &rusqlite::Row) -> Result<Factor> {
+        let kind: String = row.get(2)?;
+        let Some(kind) = FactorKind::parse(&kind) else {
+            return Err(rusqlite::Error::FromSqlConversionFailure(
+                2,
+                rusqlite::types::Type::Text,
+                format!("Unknown factor type: {}", kind).into(),
+            ));
+        };
+        // Failing closed, as treating the factor as disabled would let a
+        // password alone through
+        let sealed: Vec<u8> = row.get(3)?;
+        let Some(secret) = secret::open(&self.factor_key, &Self::factor_aad(kind, user_id), &sealed) else {
+            return Err(rusqlite::Error::FromSqlConversionFailure(
+                3,
+                rusqlite::types::Type::Blob,
+                format!("Factor secret of user {} doesn't decrypt with the server key", user_id).into(),
+            ));
+        };
+        Ok(Factor::from_db(
+            row.get(0)?,
+            row.get(1)?,
+            kind,
+            secret,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+            row.get(7)?,
+        ))
+    }
+
+    // Get all of a user's factors, including ones that aren't confirmed yet
+    pub fn get_all_factors(&self, user_id: UserID) -> Result<Vec<Factor>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT factor_id, name, kind, secret, confirmed, created_at, last_used_at, counter
+            FROM factor WHERE user_id=?1 ORDER BY factor_id",
+        )?;
+        let rows = stmt.query_map([user_id], |row| self.factor_from_row(user_id, row))?;
+
+        let mut factors = Vec::new();
+        for factor_result in rows {
+            factors.push(factor_result?);
         }
+        Ok(factors)
+    }
 
-        *saved_password_hash.unwrap() == self.hash_password(password, creation_time, num)
+    pub fn get_factor(&self, user_id: UserID, factor_id: FactorID) -> Result<Factor> {
+        self.conn.query_row(
+            "SELECT factor_id, name, kind, secret, confirmed, created_at, last_used_at, counter
+            FROM factor WHERE user_id=?1 AND factor_id=?2",
+            (user_id, factor_id),
+            |row| self.factor_from_row(user_id, row),
+        )
     }
 
-    // HANDLE FUNCTIONS --------------------------------------------------
-    pub fn add_handle_to_db(&self, user: &Account, handle: u64) -> Result<(), HandleDBError> {
-        let saved_handle = self
-            .conn
+    // Whether another of the user's factors already has this name
+    fn is_factor_name_taken(&self, user_id: UserID, name: &str, factor_id: Option<FactorID>) -> Result<bool> {
+        self.conn
             .query_row(
-                "SELECT handle_val FROM handle WHERE handle_val=?1",
-                [handle],
-                |row| row.get::<usize, u64>(0),
+                "SELECT factor_id FROM factor WHERE user_id=?1 AND name=?2",
+                (user_id, name),
+                |row| row.get::<usize, FactorID>(0),
             )
-            .is_ok();
-        if saved_handle {
-            return Err(HandleDBError::HandleAlreadyExists);
+            .optional()
+            .map(|found| found.is_some_and(|found| Some(found) != factor_id))
+    }
+
+    // Start enrolling a factor. An unconfirmed factor with the same name is
+    // replaced, so users can start over.
+    pub fn add_pending_factor(
+        &self,
+        user_id: UserID,
+        name: &str,
+        kind: FactorKind,
+        otp_secret: &[u8],
+    ) -> Result<Factor, FactorDBError> {
+        self.conn.execute(
+            "DELETE FROM factor WHERE user_id=?1 AND name=?2 AND confirmed=FALSE",
+            (user_id, name),
+        )?;
+        if self.is_factor_name_taken(user_id, name, None)? {
+            return Err(FactorDBError::NameTaken);
         }
+        let sealed = secret::seal(&self.factor_key, &Self::factor_aad(kind, user_id), otp_secret);
+        let now = Utc::now();
         self.conn.execute(
-            "INSERT INTO handle (
-            handle_val,
-            user_id
-            ) 
-            VALUES (?1, ?2)",
-            (handle, user.id()),
+            "INSERT INTO factor (user_id, name, kind, secret, confirmed, created_at)
+            VALUES (?1, ?2, ?3, ?4, FALSE, ?5)",
+            (user_id, name, kind.as_str(), sealed, now),
         )?;
-        Ok(())
+        let id = self.conn.last_insert_rowid();
+        Ok(Factor::from_db(id, name.to_string(), kind, otp_secret.to_vec(), false, now, None, None))
     }
-    
-    // Get all handles for a user
-    pub fn get_handles_for_user(&self, user_id: UserID) -> Result<Vec<u64>> {
-        let mut stmt = self.conn.prepare("SELECT handle_val FROM handle WHERE user_id=?1")?;
-        let rows = stmt.query_map([user_id], |row| row.get::<usize, u64>(0))?;
-        
-        let mut handles = Vec::new();
-        for handle_result in rows {
-            handles.push(handle_result?);
-        }
+
+    // Confirm a pending factor, and replace the user's recovery codes in the
+    // same transaction. Returns false without touching the codes when the
+    // factor was already confirmed, e.g. by a concurrent request.
+    pub fn confirm_factor(
+        &self,
+        user_id: UserID,
+        factor_id: FactorID,
+        counter: i64,
+        recovery_codes: Option<&[String]>,
+    ) -> Result<bool> {
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let rows_affected = tx.execute(
+            "UPDATE factor SET confirmed=TRUE, counter=?3, last_used_at=?4
+            WHERE user_id=?1 AND factor_id=?2 AND confirmed=FALSE",
+            (user_id, factor_id, counter, Utc::now()),
+        )?;
+        if rows_affected == 0 {
+            return Ok(false);
+        }
+        if let Some(codes) = recovery_codes {
+            self.replace_recovery_codes(&tx, user_id, codes)?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn rename_factor(&self, user_id: UserID, factor_id: FactorID, name: &str) -> Result<bool, FactorDBError> {
+        if self.is_factor_name_taken(user_id, name, Some(factor_id))? {
+            return Err(FactorDBError::NameTaken);
+        }
+        let rows_affected = self.conn.execute(
+            "UPDATE factor SET name=?3 WHERE user_id=?1 AND factor_id=?2",
+            (user_id, factor_id, name),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    // Delete a factor. Recovery codes and trusted devices go with the user's
+    // last one, as they have nothing left to stand in for.
+    pub fn delete_factor(&self, user_id: UserID, factor_id: FactorID) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "DELETE FROM factor WHERE user_id=?1 AND factor_id=?2",
+            (user_id, factor_id),
+        )?;
+        self.conn.execute(
+            "DELETE FROM recovery_code WHERE user_id=?1
+            AND NOT EXISTS (SELECT 1 FROM factor WHERE user_id=?1 AND confirmed=TRUE)",
+            [user_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM trusted_device WHERE user_id=?1
+            AND NOT EXISTS (SELECT 1 FROM factor WHERE user_id=?1 AND confirmed=TRUE)",
+            [user_id],
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    /// Checks a code against each of the user's factors. A code is only
+    /// accepted for a later time step or counter than the factor last
+    /// accepted, so an intercepted code can't be used again.
+    pub fn check_factors(&self, user: &Account, code: &str) -> bool {
+        for factor in user.factors().iter() {
+            let Some(counter) = factor.matching_counter(code, &self.mfa) else {
+                continue;
+            };
+            // Compare-and-set, so two requests racing with the same code
+            // can't both get through
+            let res = self.conn.execute(
+                "UPDATE factor SET counter=?1, last_used_at=?2
+                WHERE factor_id=?3 AND confirmed=TRUE AND (counter IS NULL OR counter < ?1)",
+                (counter, Utc::now(), factor.id()),
+            );
+            match res {
+                Ok(0) => {
+                    log::warn!("Rejected a reused code for factor #{} of user: {}", factor.id(), user.username())
+                }
+                Ok(_) => return true,
+                Err(e) => {
+                    log::error!("Failed to record use of factor #{} of user {}: {}", factor.id(), user.username(), e)
+                }
+            }
+        }
+        false
+    }
+
+    fn hash_recovery_code(&self, user_id: UserID, code: &str) -> String {
+        let message = format!("{}:{}", user_id, mfa::normalize_recovery_code(code));
+        let tag = hmac::sign(&self.recovery_code_key, message.as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    // Replace all of a user's recovery codes
+    pub fn set_recovery_codes(&self, user_id: UserID, codes: &[String]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        self.replace_recovery_codes(&tx, user_id, codes)?;
+        tx.commit()
+    }
+
+    fn replace_recovery_codes(&self, tx: &Transaction, user_id: UserID, codes: &[String]) -> Result<()> {
+        tx.execute("DELETE FROM recovery_code WHERE user_id=?1", [user_id])?;
+        for code in codes {
+            tx.execute(
+                "INSERT INTO recovery_code (user_id, code_hash) VALUES (?1, ?2)",
+                (user_id, self.hash_recovery_code(user_id, code)),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn count_recovery_codes(&self, user_id: UserID) -> Result<usize> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM recovery_code WHERE user_id=?1",
+            [user_id],
+            |row| row.get(0),
+        )
+    }
+
+    // Burn a recovery code, returning whether it was one of the user's
+    pub fn use_recovery_code(&self, user_id: UserID, code: &str) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "DELETE FROM recovery_code WHERE user_id=?1 AND code_hash=?2",
+            (user_id, self.hash_recovery_code(user_id, code)),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    fn hash_challenge(&self, challenge: &str) -> String {
+        let tag = hmac::sign(&self.challenge_key, challenge.as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    // Start the second step of a login, returning the challenge and when it
+    // expires
+    pub fn add_mfa_challenge(&self, user_id: UserID) -> Result<(String, DateTime<Utc>)> {
+        let now = Utc::now();
+        // Nothing else cleans these up, and there are never many of them
+        self.conn
+            .execute("DELETE FROM mfa_challenge WHERE expires_at <= ?1", [now])?;
+
+        let challenge = mfa::generate_challenge();
+        let expires_at = now + self.mfa.challenge_ttl();
+        self.conn.execute(
+            "INSERT INTO mfa_challenge (challenge_hash, user_id, expires_at, attempts_left)
+            VALUES (?1, ?2, ?3, ?4)",
+            (self.hash_challenge(&challenge), user_id, expires_at, self.mfa.challenge_attempts),
+        )?;
+        Ok((challenge, expires_at))
+    }
+
+    // Get the account a challenge that hasn't expired was issued to
+    pub fn get_user_by_mfa_challenge(&self, challenge: &str) -> Result<Account> {
+        let username: String = self.conn.query_row(
+            "SELECT user.username FROM mfa_challenge
+            JOIN user ON user.user_id = mfa_challenge.user_id
+            WHERE challenge_hash=?1 AND expires_at > ?2 AND attempts_left > 0",
+            (self.hash_challenge(challenge), Utc::now()),
+            |row| row.get(0),
+        )?;
+        self.get_user(&username)
+    }
+
+    // Count a wrong code against a challenge, revoking it once it's out of
+    // attempts
+    pub fn fail_mfa_challenge(&self, challenge: &str) -> Result<()> {
+        let challenge_hash = self.hash_challenge(challenge);
+        self.conn.execute(
+            "UPDATE mfa_challenge SET attempts_left = attempts_left - 1 WHERE challenge_hash=?1",
+            [&challenge_hash],
+        )?;
+        self.conn.execute(
+            "DELETE FROM mfa_challenge WHERE challenge_hash=?1 AND attempts_left <= 0",
+            [&challenge_hash],
+        )?;
+        Ok(())
+    }
+
+    // Use up a challenge. Only the caller that gets true back may log in,
+    // so a challenge can't be answered twice.
+    pub fn delete_mfa_challenge(&self, challenge: &str) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "DELETE FROM mfa_challenge WHERE challenge_hash=?1",
+            [self.hash_challenge(challenge)],
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    // TRUSTED DEVICE FUNCTIONS --------------------------------------------------
+    // Trust the device a user just logged in from with a second factor,
+    // returning it and the token it should present from now on
+    pub fn add_trusted_device(&self, user_id: UserID, name: Option<&str>) -> Result<(TrustedDevice, String)> {
+        let now = Utc::now();
+        // Nothing else cleans these up
+        self.conn
+            .execute("DELETE FROM trusted_device WHERE expires_at <= ?1", [now])?;
+
+        let expires_at = now + self.mfa.trusted_device_ttl();
+        let secret = trusted_device::generate_secret();
+        self.conn.execute(
+            "INSERT INTO trusted_device (user_id, name, created_at, expires_at, secret_hash)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            (user_id, name, now, expires_at, self.hash_trusted_device_secret(&secret)),
+        )?;
+        let device = TrustedDevice::from_db(self.conn.last_insert_rowid(), name.map(str::to_string), now, expires_at, None);
+        let token = device.token(user_id, &secret, &self.trusted_device_key);
+        Ok((device, token))
+    }
+
+    // Get the user's devices that haven't expired
+    pub fn get_trusted_devices(&self, user_id: UserID) -> Result<Vec<TrustedDevice>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT device_id, name, created_at, expires_at, last_used_at FROM trusted_device
+            WHERE user_id=?1 AND expires_at > ?2 ORDER BY device_id",
+        )?;
+        let rows = stmt.query_map((user_id, Utc::now()), |row| {
+            Ok(TrustedDevice::from_db(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        rows.collect()
+    }
+
+    // Check a token presented in place of a code, recording that the device
+    // was used if it's still trusted by the user
+    pub fn use_trusted_device(&self, user_id: UserID, token: &str) -> Result<bool> {
+        let Some((device_id, secret)) = trusted_device::verify_token(token, user_id, &self.trusted_device_key) else {
+            return Ok(false);
+        };
+        let now = Utc::now();
+        let rows_affected = self.conn.execute(
+            "UPDATE trusted_device SET last_used_at=?1
+            WHERE device_id=?2 AND user_id=?3 AND secret_hash=?4 AND expires_at > ?1",
+            (now, device_id, user_id, self.hash_trusted_device_secret(&secret)),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    fn hash_trusted_device_secret(&self, secret: &TrustedDeviceSecret) -> String {
+        let tag = hmac::sign(&self.trusted_device_key, secret);
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    // Stop trusting a device, returning whether it was one of the user's
+    pub fn delete_trusted_device(&self, user_id: UserID, device_id: TrustedDeviceID) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "DELETE FROM trusted_device WHERE device_id=?1 AND user_id=?2",
+            (device_id, user_id),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    // DEVICE KEY FUNCTIONS --------------------------------------------------
+    pub fn get_device_keys(&self, user_id: UserID) -> Result<Vec<DeviceKey>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key_id, name, public_key, created_at, last_used_at FROM device_key
+            WHERE user_id=?1 ORDER BY key_id",
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            let public_key: Vec<u8> = row.get(2)?;
+            Ok(DeviceKey::from_db(row.get(0)?, row.get(1)?, &public_key, row.get(3)?, row.get(4)?))
+        })?;
+        rows.collect()
+    }
+
+    pub fn add_device_key(&self, user_id: UserID, name: &str, public_key: &[u8]) -> Result<DeviceKey, DeviceKeyDBError> {
+        // Checked and inserted in one transaction, so concurrent requests
+        // can't both slip under the limit
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let (name_taken, key_taken, count): (bool, bool, u32) = tx.query_row(
+            "SELECT
+                EXISTS (SELECT 1 FROM device_key WHERE user_id=?1 AND name=?2),
+                EXISTS (SELECT 1 FROM device_key WHERE user_id=?1 AND public_key=?3),
+                (SELECT COUNT(*) FROM device_key WHERE user_id=?1)",
+            (user_id, name, public_key),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        if name_taken {
+            return Err(DeviceKeyDBError::NameTaken);
+        }
+        if key_taken {
+            return Err(DeviceKeyDBError::KeyTaken);
+        }
+        let limit = self.device_keys.max_keys_per_user;
+        if limit != 0 && count >= limit {
+            return Err(DeviceKeyDBError::TooManyKeys);
+        }
+
+        let now = Utc::now();
+        tx.execute(
+            "INSERT INTO device_key (user_id, name, public_key, created_at) VALUES (?1, ?2, ?3, ?4)",
+            (user_id, name, public_key, now),
+        )?;
+        let key_id = tx.last_insert_rowid();
+        tx.commit()?;
+        Ok(DeviceKey::from_db(key_id, name.to_string(), public_key, now, None))
+    }
+
+    // Delete a device key, along with any nonces waiting to be signed by it
+    pub fn delete_device_key(&self, user_id: UserID, key_id: DeviceKeyID) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "DELETE FROM device_key WHERE key_id=?1 AND user_id=?2",
+            (key_id, user_id),
+        )?;
+        self.conn.execute(
+            "DELETE FROM device_nonce WHERE key_id NOT IN (SELECT key_id FROM device_key)",
+            (),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    // Hand out a nonce for one of the user's keys to sign, returning it and
+    // when it expires. Fails with `QueryReturnedNoRows` if the key isn't
+    // the user's.
+    pub fn add_device_nonce(&self, user_id: UserID, key_id: DeviceKeyID) -> Result<(String, DateTime<Utc>)> {
+        let now = Utc::now();
+        // Nothing else cleans these up, and there are never many of them
+        self.conn
+            .execute("DELETE FROM device_nonce WHERE expires_at <= ?1", [now])?;
+
+        let nonce = device_key::generate_nonce();
+        let expires_at = now + self.device_keys.nonce_ttl();
+        let rows_affected = self.conn.execute(
+            "INSERT INTO device_nonce (nonce, key_id, expires_at)
+            SELECT ?1, key_id, ?2 FROM device_key WHERE key_id=?3 AND user_id=?4",
+            (&nonce, expires_at, key_id, user_id),
+        )?;
+        if rows_affected == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok((nonce, expires_at))
+    }
+
+    // Use up a nonce that hasn't expired, returning the key it was issued
+    // for and that key's public key. Only one caller gets it, so each nonce
+    // can only be signed once, and a wrong signature burns it too.
+    pub fn use_device_nonce(&self, nonce: &str) -> Result<(DeviceKeyID, Vec<u8>)> {
+        let (key_id, public_key) = self.conn.query_row(
+            "SELECT device_key.key_id, device_key.public_key FROM device_nonce
+            JOIN device_key ON device_key.key_id = device_nonce.key_id
+            WHERE device_nonce.nonce=?1 AND device_nonce.expires_at > ?2",
+            (nonce, Utc::now()),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let rows_affected = self
+            .conn
+            .execute("DELETE FROM device_nonce WHERE nonce=?1", [nonce])?;
+        if rows_affected == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok((key_id, public_key))
+    }
+
+    // Record that a device just logged in with its key, returning the
+    // account the key belongs to and the key's name
+    pub fn use_device_key(&self, key_id: DeviceKeyID) -> Result<(Account, String)> {
+        self.conn.execute(
+            "UPDATE device_key SET last_used_at=?1 WHERE key_id=?2",
+            (Utc::now(), key_id),
+        )?;
+        let (username, name): (String, String) = self.conn.query_row(
+            "SELECT user.username, device_key.name FROM device_key
+            JOIN user ON user.user_id = device_key.user_id
+            WHERE key_id=?1",
+            [key_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok((self.get_user(&username)?, name))
+    }
+
+    // API KEY FUNCTIONS --------------------------------------------------
+    fn hash_api_key(&self, token: &str) -> String {
+        let tag = hmac::sign(&self.api_key_key, token.as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    fn api_key_from_row(row: &rusqlite::Row) -> Result<ApiKey> {
+        let scopes: String = row.get(2)?;
+        Ok(ApiKey::from_db(
+            row.get(0)?,
+            row.get(1)?,
+            scope::parse_scopes(&scopes),
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    }
+
+    pub fn get_api_keys(&self, user_id: UserID) -> Result<Vec<ApiKey>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key_id, description, scopes, created_at, expires_at, last_used_at FROM api_key
+            WHERE user_id=?1 ORDER BY key_id",
+        )?;
+        let rows = stmt.query_map([user_id], Self::api_key_from_row)?;
+        rows.collect()
+    }
+
+    // Create a key, returning it and its token, which is only known now
+    pub fn add_api_key(
+        &self,
+        user_id: UserID,
+        description: &str,
+        scopes: &[Scope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(ApiKey, String)> {
+        let token = api_key::generate_token();
+        let now = Utc::now();
+        self.conn.execute(
+            "INSERT INTO api_key (user_id, key_hash, description, scopes, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                user_id,
+                self.hash_api_key(&token),
+                description,
+                scope::format_scopes(scopes),
+                now,
+                expires_at,
+            ),
+        )?;
+        let key = ApiKey::from_db(
+            self.conn.last_insert_rowid(),
+            description.to_string(),
+            scopes.to_vec(),
+            now,
+            expires_at,
+            None,
+        );
+        Ok((key, token))
+    }
+
+    // Get a key that hasn't expired by its token, and the ID of its owner
+    pub fn get_api_key(&self, token: &str) -> Result<(ApiKey, UserID)> {
+        self.conn.query_row(
+            "SELECT key_id, description, scopes, created_at, expires_at, last_used_at, user_id
+            FROM api_key
+            WHERE key_hash=?1 AND (expires_at IS NULL OR expires_at > ?2)",
+            (self.hash_api_key(token), Utc::now()),
+            |row| Ok((Self::api_key_from_row(row)?, row.get(6)?)),
+        )
+    }
+
+    pub fn touch_api_key(&self, key_id: ApiKeyID) -> Result<()> {
+        self.conn.execute(
+            "UPDATE api_key SET last_used_at=?1 WHERE key_id=?2",
+            (Utc::now(), key_id),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_api_key(&self, user_id: UserID, key_id: ApiKeyID) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "DELETE FROM api_key WHERE key_id=?1 AND user_id=?2",
+            (key_id, user_id),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    // DEVICE GRANT FUNCTIONS --------------------------------------------------
+    fn hash_device_code(&self, device_code: &str) -> String {
+        let tag = hmac::sign(&self.device_code_key, device_code.as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    pub fn device_grant_config(&self) -> &DeviceGrantConfig {
+        &self.device_grant
+    }
+
+    // Start authorizing a device, returning its device code and the
+    // (normalized) user code to show the user
+    pub fn add_device_grant(&self, client_id: &str) -> Result<(String, String)> {
+        let now = Utc::now();
+        // Nothing else cleans these up, and there are never many of them
+        self.conn
+            .execute("DELETE FROM device_grant WHERE expires_at <= ?1", [now])?;
+
+        let device_code = oauth::generate_device_code();
+        let expires_at = now + self.device_grant.code_ttl();
+        loop {
+            let user_code = oauth::generate_user_code();
+            let rows_affected = self.conn.execute(
+                "INSERT OR IGNORE INTO device_grant
+                (device_code_hash, user_code, client_id, status, expires_at, interval)
+                VALUES (?1, ?2, ?3, 'pending', ?4, ?5)",
+                (
+                    self.hash_device_code(&device_code),
+                    &user_code,
+                    client_id,
+                    expires_at,
+                    self.device_grant.interval,
+                ),
+            )?;
+            // Only a user code collision can make this ignore the insert
+            if rows_affected > 0 {
+                return Ok((device_code, user_code));
+            }
+        }
+    }
+
+    // Answer a pending grant the user entered the code of, returning whether
+    // there was one
+    pub fn approve_device_grant(&self, user_code: &str, user_id: UserID) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "UPDATE device_grant SET status='approved', user_id=?2
+            WHERE user_code=?1 AND status='pending' AND expires_at > ?3",
+            (user_code, user_id, Utc::now()),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    pub fn deny_device_grant(&self, user_code: &str) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "UPDATE device_grant SET status='denied'
+            WHERE user_code=?1 AND status='pending' AND expires_at > ?2",
+            (user_code, Utc::now()),
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    /// A device polling for its handle (RFC 8628 section 3.4). Polls within
+    /// the grant's interval of the last one make it 5 seconds longer, and
+    /// grants that were answered or expired are removed once reported.
+    pub fn poll_device_grant(&self, device_code: &str, client_id: &str) -> Result<DeviceGrantPoll> {
+        // status, approving username, expires_at, interval, last_polled_at
+        type GrantRow = (String, Option<String>, DateTime<Utc>, i64, Option<DateTime<Utc>>);
+        let device_code_hash = self.hash_device_code(device_code);
+        let found: Option<GrantRow> = self
+            .conn
+            .query_row(
+                "SELECT status, user.username, expires_at, interval, last_polled_at FROM device_grant
+                LEFT JOIN user ON user.user_id = device_grant.user_id
+                WHERE device_code_hash=?1 AND client_id=?2",
+                (&device_code_hash, client_id),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+            )
+            .optional()?;
+        let Some((status, username, expires_at, interval, last_polled_at)) = found else {
+            return Ok(DeviceGrantPoll::Invalid);
+        };
+
+        let now = Utc::now();
+        if expires_at <= now {
+            self.conn.execute("DELETE FROM device_grant WHERE device_code_hash=?1", [&device_code_hash])?;
+            return Ok(DeviceGrantPoll::Expired);
+        }
+        let too_soon = last_polled_at.is_some_and(|last| last + chrono::Duration::seconds(interval) > now);
+        if too_soon {
+            self.conn.execute(
+                "UPDATE device_grant SET interval = interval + 5, last_polled_at=?2 WHERE device_code_hash=?1",
+                (&device_code_hash, now),
+            )?;
+            return Ok(DeviceGrantPoll::SlowDown);
+        }
+
+        match (status.as_str(), username) {
+            ("approved", Some(username)) => {
+                // Only the poll that removes the grant gets the handle
+                let rows_affected = self
+                    .conn
+                    .execute("DELETE FROM device_grant WHERE device_code_hash=?1", [&device_code_hash])?;
+                if rows_affected == 0 {
+                    return Ok(DeviceGrantPoll::Invalid);
+                }
+                Ok(DeviceGrantPoll::Approved(self.get_user(&username)?))
+            },
+            ("denied", _) => {
+                self.conn.execute("DELETE FROM device_grant WHERE device_code_hash=?1", [&device_code_hash])?;
+                Ok(DeviceGrantPoll::Denied)
+            },
+            _ => {
+                self.conn.execute(
+                    "UPDATE device_grant SET last_polled_at=?2 WHERE device_code_hash=?1",
+                    (&device_code_hash, now),
+                )?;
+                Ok(DeviceGrantPoll::Pending)
+            },
+        }
+    }
+
+    // OIDC FUNCTIONS --------------------------------------------------
+    pub fn oidc_config(&self) -> &OidcConfig {
+        &self.oidc
+    }
+
+    fn hash_client_secret(&self, client_secret: &str) -> String {
+        let tag = hmac::sign(&self.client_secret_key, client_secret.as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    fn hash_authorization_code(&self, code: &str) -> String {
+        let tag = hmac::sign(&self.authorization_code_key, code.as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    fn oauth_client_from_row(row: &rusqlite::Row) -> Result<OAuthClient> {
+        let redirect_uris: String = row.get(2)?;
+        let redirect_uris = serde_json::from_str(&redirect_uris)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e)))?;
+        let secret_hash: Option<String> = row.get(3)?;
+        Ok(OAuthClient::from_db(
+            row.get(0)?,
+            row.get(1)?,
+            redirect_uris,
+            secret_hash.is_some(),
+            row.get(4)?,
+        ))
+    }
+
+    // Register a client, returning it and, for confidential clients, its
+    // secret
+    pub fn add_oauth_client(
+        &self,
+        owner_id: UserID,
+        name: &str,
+        redirect_uris: &[String],
+        confidential: bool,
+    ) -> Result<(OAuthClient, Option<String>)> {
+        let client_id = oauth::generate_client_id();
+        let client_secret = confidential.then(oauth::generate_client_secret);
+        let now = Utc::now();
+        let redirect_uris_json = serde_json::to_string(redirect_uris).expect("strings serialize");
+        self.conn.execute(
+            "INSERT INTO oauth_client (client_id, owner_id, name, redirect_uris, secret_hash, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                &client_id,
+                owner_id,
+                name,
+                redirect_uris_json,
+                client_secret.as_deref().map(|secret| self.hash_client_secret(secret)),
+                now,
+            ),
+        )?;
+        let client = OAuthClient::from_db(client_id, name.to_string(), redirect_uris.to_vec(), confidential, now);
+        Ok((client, client_secret))
+    }
+
+    // Get the clients a user registered
+    pub fn get_oauth_clients(&self, owner_id: UserID) -> Result<Vec<OAuthClient>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT client_id, name, redirect_uris, secret_hash, created_at FROM oauth_client
+            WHERE owner_id=?1 ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map([owner_id], Self::oauth_client_from_row)?;
+        rows.collect()
+    }
+
+    pub fn get_oauth_client(&self, client_id: &str) -> Result<OAuthClient> {
+        self.conn.query_row(
+            "SELECT client_id, name, redirect_uris, secret_hash, created_at FROM oauth_client
+            WHERE client_id=?1",
+            [client_id],
+            Self::oauth_client_from_row,
+        )
+    }
+
+    // Check the secret a confidential client sent. Public clients have none,
+    // so this is always false for them.
+    pub fn check_client_secret(&self, client_id: &str, client_secret: &str) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM oauth_client WHERE client_id=?1 AND secret_hash=?2)",
+            (client_id, self.hash_client_secret(client_secret)),
+            |row| row.get(0),
+        )
+    }
+
+    // Delete one of the user's clients, along with any codes and handles
+    // issued to it
+    pub fn delete_oauth_client(&self, owner_id: UserID, client_id: &str) -> Result<bool> {
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let rows_affected = tx.execute(
+            "DELETE FROM oauth_client WHERE client_id=?1 AND owner_id=?2",
+            (client_id, owner_id),
+        )?;
+        if rows_affected > 0 {
+            tx.execute("DELETE FROM handle WHERE client_id=?1", [client_id])?;
+        }
+        tx.execute(
+            "DELETE FROM authorization_code WHERE client_id NOT IN (SELECT client_id FROM oauth_client)",
+            (),
+        )?;
+        tx.commit()?;
+        Ok(rows_affected > 0)
+    }
+
+    pub fn add_authorization_code(&self, grant: &AuthorizationGrant) -> Result<String> {
+        let now = Utc::now();
+        // Nothing else cleans these up, and there are never many of them
+        self.conn
+            .execute("DELETE FROM authorization_code WHERE expires_at <= ?1", [now])?;
+
+        let code = oauth::generate_authorization_code();
+        self.conn.execute(
+            "INSERT INTO authorization_code
+            (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            (
+                self.hash_authorization_code(&code),
+                &grant.client_id,
+                grant.user_id,
+                &grant.redirect_uri,
+                &grant.scope,
+                &grant.nonce,
+                &grant.code_challenge,
+                grant.auth_time,
+                now + self.oidc.code_ttl(),
+            ),
+        )?;
+        Ok(code)
+    }
+
+    // Use up an authorization code that hasn't expired. Only one caller
+    // gets the grant back, so a code can't be redeemed twice.
+    pub fn use_authorization_code(&self, code: &str) -> Result<Option<AuthorizationGrant>> {
+        let code_hash = self.hash_authorization_code(code);
+        let grant = self
+            .conn
+            .query_row(
+                "SELECT client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time
+                FROM authorization_code WHERE code_hash=?1 AND expires_at > ?2",
+                (&code_hash, Utc::now()),
+                |row| {
+                    Ok(AuthorizationGrant {
+                        client_id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        redirect_uri: row.get(2)?,
+                        scope: row.get(3)?,
+                        nonce: row.get(4)?,
+                        code_challenge: row.get(5)?,
+                        auth_time: row.get(6)?,
+                    })
+                },
+            )
+            .optional()?;
+        let rows_affected = self
+            .conn
+            .execute("DELETE FROM authorization_code WHERE code_hash=?1", [&code_hash])?;
+        Ok(grant.filter(|_| rows_affected > 0))
+    }
+
+    /// The key to sign tokens with. The next key is published
+    /// `key_publication` before the current one is `key_rotation` old, and
+    /// takes over then, so services that cache the JWKS already have it.
+    /// Keys replaced more than `key_retention` ago are deleted.
+    pub fn current_signing_key(&self) -> Result<SigningKey> {
+        let now = Utc::now();
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        tx.execute(
+            "DELETE FROM signing_key WHERE retired_at <= ?1",
+            [now - self.oidc.key_retention()],
+        )?;
+
+        // ID, sealed PKCS#8 document, and when it was created and took over
+        type KeyRow = (String, Vec<u8>, DateTime<Utc>, DateTime<Utc>);
+        let current: Option<KeyRow> = tx
+            .query_row(
+                "SELECT key_id, private_key, created_at, activates_at FROM signing_key
+                WHERE retired_at IS NULL AND activates_at <= ?1 ORDER BY activates_at DESC LIMIT 1",
+                [now],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let Some((key_id, sealed, created_at, activated_at)) = current else {
+            // Nothing has been signed yet, so there's no JWKS cached anywhere
+            // to be missing the key
+            let (key_id, pkcs8) = self.add_signing_key(&tx, now, now)?;
+            tx.commit()?;
+            log::info!("Created signing key {}", key_id);
+            return Ok(SigningKey::from_pkcs8(key_id, &pkcs8, now).expect("generated keys are valid"));
+        };
+
+        // The keys this one took over from
+        let retired = tx.execute(
+            "UPDATE signing_key SET retired_at=?1 WHERE retired_at IS NULL AND activates_at < ?1",
+            [activated_at],
+        )?;
+        if retired > 0 {
+            log::info!("Rotated to signing key {}", key_id);
+        }
+        let rotation_due = activated_at + self.oidc.key_rotation();
+        if rotation_due - self.oidc.key_publication() <= now {
+            let published: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM signing_key WHERE activates_at > ?1)",
+                [now],
+                |row| row.get(0),
+            )?;
+            if !published {
+                // If nothing asked for a key in the meantime, the current one
+                // signs for a little longer rather than the next one early
+                let activates_at = rotation_due.max(now + self.oidc.key_publication());
+                let (next_id, _) = self.add_signing_key(&tx, now, activates_at)?;
+                log::info!("Published signing key {}, which signs from {}", next_id, activates_at);
+            }
+        }
+        tx.commit()?;
+
+        let key = secret::open(&self.signing_key_key, key_id.as_bytes(), &sealed)
+            .and_then(|pkcs8| SigningKey::from_pkcs8(key_id.clone(), &pkcs8, created_at));
+        // Like factor secrets, fail closed rather than sign with something
+        // else
+        key.ok_or_else(|| {
+            log::error!("Signing key {} can't be decrypted; was the secret key changed?", key_id);
+            rusqlite::Error::InvalidQuery
+        })
+    }
+
+    // Generate a key that signs from `activates_at`, returning its ID and
+    // PKCS#8 document
+    fn add_signing_key(
+        &self,
+        tx: &Transaction,
+        now: DateTime<Utc>,
+        activates_at: DateTime<Utc>,
+    ) -> Result<(String, Vec<u8>)> {
+        let (key_id, pkcs8, public_key) = jwt::generate_signing_key();
+        tx.execute(
+            "INSERT INTO signing_key (key_id, private_key, public_key, created_at, activates_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                &key_id,
+                secret::seal(&self.signing_key_key, key_id.as_bytes(), &pkcs8),
+                public_key,
+                now,
+                activates_at,
+            ),
+        )?;
+        Ok((key_id, pkcs8))
+    }
+
+    // The public keys tokens may currently be signed with, and the next one,
+    // newest first
+    pub fn get_public_keys(&self) -> Result<Vec<Jwk>> {
+        // Rotates keys that are due, and publishes the next one when it's time
+        self.current_signing_key()?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key_id, public_key FROM signing_key ORDER BY activates_at DESC")?;
+        let rows = stmt.query_map([], |row| {
+            let public_key: Vec<u8> = row.get(1)?;
+            Ok(jwt::public_jwk(row.get(0)?, &public_key))
+        })?;
+        rows.collect()
+    }
+
+    // HANDLE FUNCTIONS --------------------------------------------------
+    pub fn session_config(&self) -> &SessionConfig {
+        &self.session
+    }
+
+    /// Handles that reached their absolute expiry or went unused for longer
+    /// than the idle TTL are treated as if they didn't exist. Returns the
+    /// `:now` and `:idle_cutoff` parameters for `HANDLE_ACTIVE`.
+    fn handle_cutoffs(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        let now = Utc::now();
+        (now, now - self.session.idle_ttl())
+    }
+
+    /// Handles are only stored as an HMAC under a key derived from the
+    /// server secret, never in plaintext
+    fn hash_handle(&self, token: &HandleToken) -> String {
+        let tag = hmac::sign(&self.handle_key, token.as_str().as_bytes());
+        Base64Unpadded::encode_string(tag.as_ref())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_handle_to_db(
+        &self,
+        user: &Account,
+        token: &HandleToken,
+        authenticated_at: DateTime<Utc>,
+        mfa_at: Option<DateTime<Utc>>,
+        expires_by: Option<DateTime<Utc>>,
+        client: &ClientInfo,
+        scopes: Option<&[Scope]>,
+        client_id: Option<&str>,
+    ) -> Result<Handle, HandleDBError> {
+        let handle_hash = self.hash_handle(token);
+        let saved_handle = self
+            .conn
+            .query_row(
+                "SELECT handle_id FROM handle WHERE handle_hash=?1",
+                [&handle_hash],
+                |row| row.get::<usize, HandleID>(0),
+            )
+            .is_ok();
+        if saved_handle {
+            return Err(HandleDBError::HandleAlreadyExists);
+        }
+        let now = Utc::now();
+        let expires_at = now + self.session.absolute_ttl();
+        // Otherwise handles could be swapped for new ones forever
+        let expires_at = expires_by.map_or(expires_at, |limit| expires_at.min(limit));
+        self.conn.execute(
+            "INSERT INTO handle (
+            handle_hash,
+            user_id,
+            created_at,
+            expires_at,
+            last_used_at,
+            authenticated_at,
+            mfa_at,
+            user_agent,
+            ip_address,
+            device_name,
+            scopes,
+            client_id
+            ) 
+            VALUES (?1, ?2, ?3, ?4, ?3, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            (
+                handle_hash,
+                user.id(),
+                now,
+                expires_at,
+                authenticated_at,
+                mfa_at,
+                &client.user_agent,
+                &client.ip_address,
+                &client.device_name,
+                scopes.map(scope::format_scopes),
+                client_id,
+            ),
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.evict_handles_over_limit(user.id())?;
+        Ok(Handle::from_db(
+            id,
+            Some(token.clone()),
+            now,
+            expires_at,
+            now,
+            authenticated_at,
+            mfa_at,
+            client.clone(),
+            scopes.map(<[Scope]>::to_vec),
+        ))
+    }
+
+    // Keep only the user's most recently used handles, up to the limit
+    fn evict_handles_over_limit(&self, user_id: UserID) -> Result<usize> {
+        let limit = self.session.max_handles_per_user;
+        if limit == 0 {
+            return Ok(0);
+        }
+        let evicted = self.conn.execute(
+            "DELETE FROM handle WHERE user_id=?1 AND handle_id NOT IN (
+                SELECT handle_id FROM handle WHERE user_id=?1
+                ORDER BY last_used_at DESC, handle_id DESC
+                LIMIT ?2
+            )",
+            (user_id, limit),
+        )?;
+        if evicted > 0 {
+            metrics::HANDLES_EVICTED.add(evicted as u64);
+            log::info!("Evicted {} least recently used handles of user {}", evicted, user_id);
+        }
+        Ok(evicted)
+    }
+
+    // Delete handles that have expired or whose user no longer exists
+    pub fn purge_handles(&self) -> Result<PurgeStats> {
+        let (now, idle_cutoff) = self.handle_cutoffs();
+        let expired = self.conn.execute(
+            &format!("DELETE FROM handle WHERE NOT ({HANDLE_ACTIVE})"),
+            named_params! {":now": now, ":idle_cutoff": idle_cutoff},
+        )?;
+        let orphaned = self.conn.execute(
+            "DELETE FROM handle WHERE user_id NOT IN (SELECT user_id FROM user)",
+            (),
+        )?;
+        Ok(PurgeStats { expired, orphaned })
+    }
+
+    fn handle_from_row(row: &rusqlite::Row, value: Option<HandleToken>) -> Result<Handle> {
+        Ok(Handle::from_db(
+            row.get(0)?,
+            value,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            ClientInfo {
+                user_agent: row.get(6)?,
+                ip_address: row.get(7)?,
+                device_name: row.get(8)?,
+            },
+            row.get::<usize, Option<String>>(9)?.as_deref().map(scope::parse_scopes),
+        ))
+    }
+    
+    // Get a handle that hasn't expired by its token
+    pub fn get_handle(&self, token: &HandleToken) -> Result<Handle> {
+        let (now, idle_cutoff) = self.handle_cutoffs();
+        self.conn.query_row(
+            &format!(
+                "SELECT handle_id, created_at, expires_at, last_used_at, authenticated_at, mfa_at,
+                user_agent, ip_address, device_name, scopes
+                FROM handle
+                WHERE handle_hash=:handle_hash AND {HANDLE_ACTIVE}"
+            ),
+            named_params! {
+                ":handle_hash": self.hash_handle(token),
+                ":now": now,
+                ":idle_cutoff": idle_cutoff,
+            },
+            |row| Self::handle_from_row(row, Some(token.clone())),
+        )
+    }
+
+    // Get all handles for a user that haven't expired
+    pub fn get_handles_for_user(&self, user_id: UserID) -> Result<Vec<Handle>> {
+        let (now, idle_cutoff) = self.handle_cutoffs();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT handle_id, created_at, expires_at, last_used_at, authenticated_at, mfa_at,
+            user_agent, ip_address, device_name, scopes
+            FROM handle
+            WHERE user_id=:user_id AND {HANDLE_ACTIVE}"
+        ))?;
+        let rows = stmt.query_map(
+            named_params! {":user_id": user_id, ":now": now, ":idle_cutoff": idle_cutoff},
+            |row| Self::handle_from_row(row, None),
+        )?;
+        
+        let mut handles = Vec::new();
+        for handle_result in rows {
+            handles.push(handle_result?);
+        }
         
         Ok(handles)
     }
     
-    // Check if a handle belongs to a user
-    pub fn is_handle_owned_by_user(&self, handle: u64, user_id: UserID) -> bool {
+    // Get the account a handle that hasn't expired was issued to
+    pub fn get_user_by_handle_id(&self, handle_id: HandleID) -> Result<Account> {
+        let (now, idle_cutoff) = self.handle_cutoffs();
+        let username: String = self.conn.query_row(
+            &format!(
+                "SELECT user.username FROM handle
+                JOIN user ON user.user_id = handle.user_id
+                WHERE handle.handle_id=:handle_id AND {HANDLE_ACTIVE}"
+            ),
+            named_params! {":handle_id": handle_id, ":now": now, ":idle_cutoff": idle_cutoff},
+            |row| row.get(0),
+        )?;
+        self.get_user(&username)
+    }
+
+    // Mark a handle as used now, returning the new last-used time
+    pub fn touch_handle(&self, handle_id: HandleID) -> Result<DateTime<Utc>> {
+        let now = Utc::now();
+        self.conn.execute(
+            "UPDATE handle SET last_used_at=?1 WHERE handle_id=?2",
+            (now, handle_id),
+        )?;
+        Ok(now)
+    }
+
+    pub fn set_handle_authenticated(
+        &self,
+        handle_id: HandleID,
+        authenticated_at: DateTime<Utc>,
+        mfa_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE handle SET authenticated_at=?1, mfa_at=?2 WHERE handle_id=?3",
+            (authenticated_at, mfa_at, handle_id),
+        )?;
+        Ok(())
+    }
+
+    // Check if a handle that hasn't expired belongs to a user
+    pub fn is_handle_owned_by_user(&self, handle_id: HandleID, user_id: UserID) -> bool {
+        let (now, idle_cutoff) = self.handle_cutoffs();
         self.conn
             .query_row(
-                "SELECT handle_val FROM handle WHERE handle_val=?1 AND user_id=?2",
-                [handle, user_id],
-                |row| row.get::<usize, u64>(0),
+                &format!(
+                    "SELECT handle_id FROM handle
+                    WHERE handle_id=:handle_id AND user_id=:user_id AND {HANDLE_ACTIVE}"
+                ),
+                named_params! {
+                    ":handle_id": handle_id,
+                    ":user_id": user_id,
+                    ":now": now,
+                    ":idle_cutoff": idle_cutoff,
+                },
+                |row| row.get::<usize, HandleID>(0),
             )
             .is_ok()
     }
     
     // Delete a handle
-    pub fn delete_handle(&self, handle: u64, user_id: UserID) -> Result<bool> {
+    pub fn delete_handle(&self, handle_id: HandleID, user_id: UserID) -> Result<bool> {
         let rows_affected = self.conn.execute(
-            "DELETE FROM handle WHERE handle_val=?1 AND user_id=?2",
-            [handle, user_id],
+            "DELETE FROM handle WHERE handle_id=?1 AND user_id=?2",
+            (handle_id, user_id),
         )?;
         
         Ok(rows_affected > 0)
     }
-}
\ No newline at end of file
+
+    // Delete every handle of a user except one, returning how many went
+    pub fn delete_other_handles(&self, user_id: UserID, keep: HandleID) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM handle WHERE user_id=?1 AND handle_id<>?2",
+            (user_id, keep),
+        )
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use totp_rs::{Algorithm, TOTP};
+
+    use super::*;
+    use crate::{config::PasswordConfig, password::PasswordHasher};
+
+    // An in-memory database with password costs cheap enough for tests
+    fn test_db() -> Database {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            database_path: ":memory:".to_string(),
+            secret_key_path: dir.path().join("secret.key").to_string_lossy().into_owned(),
+            password: PasswordConfig {
+                memory_cost: 64,
+                time_cost: 1,
+                ..PasswordConfig::default()
+            },
+            ..Config::default()
+        };
+        // The secret is only read once per process, so the file isn't
+        // needed once the first database has it
+        let db = Database::with_config(config);
+        db.migrate().unwrap();
+        db
+    }
+
+    fn add_user(db: &Database, username: &str) -> Account {
+        db.add_user(username, "hunter2").unwrap();
+        db.get_user(username).unwrap()
+    }
+
+    fn password_hash(db: &Database, username: &str) -> String {
+        db.conn
+            .query_row("SELECT password_hash FROM user WHERE username=?1", [username], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn add_handle(db: &Database, user: &Account, expires_by: Option<DateTime<Utc>>, client_id: Option<&str>) -> Handle {
+        let token = HandleToken::generate();
+        db.add_handle_to_db(user, &token, Utc::now(), None, expires_by, &ClientInfo::default(), None, client_id)
+            .unwrap()
+    }
+
+    #[test]
+    fn migrations_only_run_once() {
+        let db = test_db();
+        db.migrate().unwrap();
+        let version: usize = db.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn legacy_hashes_are_upgraded_on_login() {
+        let db = test_db();
+        add_user(&db, "alice");
+        let salt = password::generate_salt();
+        db.conn
+            .execute(
+                "UPDATE user SET password_hash=?1, salt=?2 WHERE username='alice'",
+                (LegacySha256Hasher.hash("hunter2", &salt), &salt),
+            )
+            .unwrap();
+
+        assert!(!db.check_login("alice", "hunter3"));
+        assert!(password_hash(&db, "alice").starts_with("$abuelo-sha256$"));
+        assert!(db.check_login("alice", "hunter2"));
+        assert!(password_hash(&db, "alice").starts_with("$argon2id$"));
+        assert!(db.check_login("alice", "hunter2"));
+    }
+
+    #[test]
+    fn weaker_hashes_are_upgraded_on_login() {
+        let mut db = test_db();
+        add_user(&db, "alice");
+        let weak = password_hash(&db, "alice");
+        db.hashers = Hashers::from_config(&PasswordConfig {
+            memory_cost: 128,
+            time_cost: 1,
+            ..PasswordConfig::default()
+        });
+
+        assert!(db.check_login("alice", "hunter2"));
+        let upgraded = password_hash(&db, "alice");
+        assert_ne!(upgraded, weak);
+        assert!(upgraded.contains("m=128"));
+    }
+
+    #[test]
+    fn handles_expire() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let idle = add_handle(&db, &alice, None, None);
+        let old = add_handle(&db, &alice, None, None);
+        let fresh = add_handle(&db, &alice, None, None);
+        let now = Utc::now();
+        db.conn
+            .execute(
+                "UPDATE handle SET last_used_at=?1 WHERE handle_id=?2",
+                (now - db.session.idle_ttl() - Duration::seconds(1), idle.id()),
+            )
+            .unwrap();
+        db.conn
+            .execute("UPDATE handle SET expires_at=?1 WHERE handle_id=?2", (now, old.id()))
+            .unwrap();
+
+        assert!(db.get_handle(idle.get().unwrap()).is_err());
+        assert!(db.get_handle(old.get().unwrap()).is_err());
+        assert!(db.get_handle(fresh.get().unwrap()).is_ok());
+        assert_eq!(db.get_handles_for_user(alice.id()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn handles_are_only_stored_hashed() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let handle = add_handle(&db, &alice, None, None);
+        let token = handle.get().unwrap();
+        let stored: String = db.conn
+            .query_row("SELECT handle_hash FROM handle WHERE handle_id=?1", [handle.id()], |row| row.get(0))
+            .unwrap();
+        assert_ne!(stored, token.to_string());
+        assert_eq!(db.get_handle(token).unwrap().id(), handle.id());
+    }
+
+    #[test]
+    fn handles_made_from_others_expire_with_them() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let parent = add_handle(&db, &alice, None, None);
+        let child = add_handle(&db, &alice, Some(parent.expires_at()), None);
+        assert_eq!(child.expires_at(), parent.expires_at());
+        let later = add_handle(&db, &alice, Some(Utc::now() + db.session.absolute_ttl() * 2), None);
+        assert!(later.expires_at() <= Utc::now() + db.session.absolute_ttl());
+    }
+
+    #[test]
+    fn purge_removes_expired_and_orphaned_handles() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let bob = add_user(&db, "bob");
+        let expired = add_handle(&db, &alice, None, None);
+        let kept = add_handle(&db, &alice, None, None);
+        add_handle(&db, &bob, None, None);
+        db.conn
+            .execute("UPDATE handle SET expires_at=?1 WHERE handle_id=?2", (Utc::now(), expired.id()))
+            .unwrap();
+        // Only databases written without foreign keys enforced have these
+        db.conn.pragma_update(None, "foreign_keys", false).unwrap();
+        db.conn.execute("DELETE FROM user WHERE username='bob'", ()).unwrap();
+
+        let stats = db.purge_handles().unwrap();
+        assert_eq!((stats.expired, stats.orphaned), (1, 1));
+        let left: Vec<HandleID> = db.conn
+            .prepare("SELECT handle_id FROM handle")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(left, vec![kept.id()]);
+    }
+
+    #[test]
+    fn least_recently_used_handles_are_evicted() {
+        let mut db = test_db();
+        db.session.max_handles_per_user = 2;
+        let alice = add_user(&db, "alice");
+        let first = add_handle(&db, &alice, None, None);
+        let second = add_handle(&db, &alice, None, None);
+        db.conn
+            .execute(
+                "UPDATE handle SET last_used_at=?1 WHERE handle_id=?2",
+                (Utc::now() + Duration::seconds(1), first.id()),
+            )
+            .unwrap();
+        add_handle(&db, &alice, None, None);
+
+        assert_eq!(db.get_handles_for_user(alice.id()).unwrap().len(), 2);
+        assert!(db.get_handle(first.get().unwrap()).is_ok());
+        assert!(db.get_handle(second.get().unwrap()).is_err());
+    }
+
+    #[test]
+    fn recovery_codes_work_once() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let bob = add_user(&db, "bob");
+        let codes = mfa::generate_recovery_codes(3);
+        db.set_recovery_codes(alice.id(), &codes).unwrap();
+
+        assert!(!db.use_recovery_code(bob.id(), &codes[0]).unwrap());
+        // Typed in uppercase without the dash
+        assert!(db.use_recovery_code(alice.id(), &codes[0].replace('-', "").to_uppercase()).unwrap());
+        assert!(!db.use_recovery_code(alice.id(), &codes[0]).unwrap());
+        assert_eq!(db.count_recovery_codes(alice.id()).unwrap(), 2);
+    }
+
+    #[test]
+    fn factor_codes_cannot_be_replayed() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let secret = mfa::generate_otp_secret();
+        let factor = db.add_pending_factor(alice.id(), "phone", FactorKind::Totp, &secret).unwrap();
+        assert!(db.confirm_factor(alice.id(), factor.id(), 0, None).unwrap());
+        let alice = db.get_user("alice").unwrap();
+
+        let code = TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, None, "alice".to_string()).unwrap().generate_current().unwrap();
+        assert!(db.check_factors(&alice, &code));
+        assert!(!db.check_factors(&alice, &code));
+    }
+
+    #[test]
+    fn device_codes_are_redeemed_once() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let (device_code, user_code) = db.add_device_grant("tv").unwrap();
+        assert!(matches!(db.poll_device_grant(&device_code, "tv").unwrap(), DeviceGrantPoll::Pending));
+        assert!(matches!(db.poll_device_grant(&device_code, "tv").unwrap(), DeviceGrantPoll::SlowDown));
+
+        assert!(db.approve_device_grant(&user_code, alice.id()).unwrap());
+        assert!(!db.deny_device_grant(&user_code).unwrap());
+        db.conn.execute("UPDATE device_grant SET last_polled_at=NULL", ()).unwrap();
+        assert!(matches!(db.poll_device_grant(&device_code, "other").unwrap(), DeviceGrantPoll::Invalid));
+        match db.poll_device_grant(&device_code, "tv").unwrap() {
+            DeviceGrantPoll::Approved(user) => assert_eq!(user.id(), alice.id()),
+            poll => panic!("Expected an approved grant, got {:?}", poll),
+        }
+        assert!(matches!(db.poll_device_grant(&device_code, "tv").unwrap(), DeviceGrantPoll::Invalid));
+    }
+
+    #[test]
+    fn authorization_codes_are_redeemed_once() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let (client, _) = db
+            .add_oauth_client(alice.id(), "app", &["https://example.com/callback".to_string()], false)
+            .unwrap();
+        let grant = AuthorizationGrant {
+            client_id: client.client_id().to_string(),
+            user_id: alice.id(),
+            redirect_uri: "https://example.com/callback".to_string(),
+            scope: "openid".to_string(),
+            nonce: None,
+            code_challenge: "challenge".to_string(),
+            auth_time: None,
+        };
+        let code = db.add_authorization_code(&grant).unwrap();
+
+        assert!(db.use_authorization_code("not a code").unwrap().is_none());
+        let redeemed = db.use_authorization_code(&code).unwrap().unwrap();
+        assert_eq!((redeemed.client_id, redeemed.user_id), (grant.client_id, alice.id()));
+        assert!(db.use_authorization_code(&code).unwrap().is_none());
+    }
+
+    #[test]
+    fn deleting_a_client_revokes_its_handles() {
+        let db = test_db();
+        let alice = add_user(&db, "alice");
+        let (client, _) = db
+            .add_oauth_client(alice.id(), "app", &["https://example.com/callback".to_string()], false)
+            .unwrap();
+        let issued = add_handle(&db, &alice, None, Some(client.client_id()));
+        let own = add_handle(&db, &alice, None, None);
+
+        assert!(db.delete_oauth_client(alice.id(), client.client_id()).unwrap());
+        assert!(db.get_handle(issued.get().unwrap()).is_err());
+        assert!(db.get_handle(own.get().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn signing_keys_are_published_before_they_sign() {
+        let db = test_db();
+        let first = db.current_signing_key().unwrap();
+        assert_eq!(db.get_public_keys().unwrap().len(), 1);
+
+        // Into the publication window before rotation is due
+        let activated_at = Utc::now() - db.oidc.key_rotation() + db.oidc.key_publication() / 2;
+        db.conn
+            .execute("UPDATE signing_key SET activates_at=?1", [activated_at])
+            .unwrap();
+        assert_eq!(db.current_signing_key().unwrap().id(), first.id());
+        assert_eq!(db.get_public_keys().unwrap().len(), 2);
+        let next: String = db.conn
+            .query_row("SELECT key_id FROM signing_key WHERE key_id<>?1", [first.id()], |row| row.get(0))
+            .unwrap();
+
+        db.conn
+            .execute("UPDATE signing_key SET activates_at=?1 WHERE key_id=?2", (Utc::now(), &next))
+            .unwrap();
+        assert_eq!(db.current_signing_key().unwrap().id(), next);
+        let retired: Option<DateTime<Utc>> = db.conn
+            .query_row("SELECT retired_at FROM signing_key WHERE key_id=?1", [first.id()], |row| row.get(0))
+            .unwrap();
+        assert!(retired.is_some());
+        // Still published, for tokens it signed
+        assert_eq!(db.get_public_keys().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,74 @@
+use base64ct::{Base64, Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+/// Row ID of a device key
+pub type DeviceKeyID = i64;
+
+const PUBLIC_KEY_LEN: usize = 32;
+const NONCE_PREFIX: &str = "abldn_";
+const NONCE_BYTES: usize = 32;
+
+/// An Ed25519 public key a device, such as an AbleOS machine or a CI runner,
+/// logs in with instead of the user's password
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeviceKey {
+    id: DeviceKeyID,
+    name: String,
+    // Standard base64, as sent when registering it
+    public_key: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl DeviceKey {
+    pub fn from_db(
+        id: DeviceKeyID,
+        name: String,
+        public_key: &[u8],
+        created_at: DateTime<Utc>,
+        last_used_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        DeviceKey {
+            id,
+            name,
+            public_key: Base64::encode_string(public_key),
+            created_at,
+            last_used_at,
+        }
+    }
+
+    pub fn id(&self) -> DeviceKeyID {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Decodes a raw 32 byte Ed25519 public key from standard base64
+pub fn parse_public_key(public_key: &str) -> Option<Vec<u8>> {
+    let public_key = Base64::decode_vec(public_key.trim()).ok()?;
+    (public_key.len() == PUBLIC_KEY_LEN).then_some(public_key)
+}
+
+/// What a device signs to log in. Nonces are single-use and short-lived, so
+/// a captured signature is no good to anyone else.
+pub fn generate_nonce() -> String {
+    let mut bytes = [0u8; NONCE_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", NONCE_PREFIX, Base64UrlUnpadded::encode_string(&bytes))
+}
+
+/// Checks a standard base64 Ed25519 signature over the nonce, exactly as it
+/// was handed out
+pub fn verify(public_key: &[u8], nonce: &str, signature: &str) -> bool {
+    let Ok(signature) = Base64::decode_vec(signature.trim()) else {
+        return false;
+    };
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(nonce.as_bytes(), &signature)
+        .is_ok()
+}
@@ -1,20 +1,190 @@
+use std::fmt::Display;
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
 use rusqlite::Result;
 
 use crate::{
     account::{Account, UserID},
     database::{Database, HandleDBError},
+    scope::Scope,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
-pub struct Handle(u64);
+/// Row ID of a handle. Unlike the handle's value it isn't a secret, so it's
+/// what gets shown when listing a user's handles.
+pub type HandleID = i64;
+
+/// The secret a client presents to use a handle: `abl_` followed by 256
+/// random bits in base64url. Numeric handles issued before this format are
+/// still accepted until they expire.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(transparent)]
+pub struct HandleToken(String);
+
+impl HandleToken {
+    pub const PREFIX: &'static str = "abl_";
+    const RANDOM_BYTES: usize = 32;
+
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; Self::RANDOM_BYTES];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        HandleToken(format!("{}{}", Self::PREFIX, Base64UrlUnpadded::encode_string(&bytes)))
+    }
+
+    pub fn parse(token: &str) -> Option<Self> {
+        match token.strip_prefix(Self::PREFIX) {
+            Some(encoded) => {
+                let bytes = Base64UrlUnpadded::decode_vec(encoded).ok()?;
+                (bytes.len() == Self::RANDOM_BYTES).then(|| HandleToken(token.to_string()))
+            }
+            // Legacy handles, normalized so they hash the same as before
+            None => token.parse::<u64>().ok().map(Self::from_legacy),
+        }
+    }
+
+    pub fn from_legacy(value: u64) -> Self {
+        HandleToken(value.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        !self.0.starts_with(Self::PREFIX)
+    }
+}
+
+impl Display for HandleToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Legacy handles were sent as JSON numbers, so accept those as well
+impl<'de> serde::Deserialize<'de> for HandleToken {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Legacy(u64),
+            Token(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Legacy(value) => Ok(HandleToken::from_legacy(value)),
+            Repr::Token(token) => HandleToken::parse(&token)
+                .ok_or_else(|| serde::de::Error::custom("invalid handle")),
+        }
+    }
+}
+
+/// User agents are cut off at this many characters before being stored
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Where a handle was created from, so users can recognise their sessions.
+/// All of it comes from the client, so it's only a hint.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    // Sent by the client when logging in
+    pub device_name: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn with_device_name(mut self, device_name: Option<&str>) -> Self {
+        self.device_name = device_name.filter(|name| !name.is_empty()).map(str::to_string);
+        self
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_agent = request
+            .headers()
+            .get_one("User-Agent")
+            .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect());
+        Outcome::Success(ClientInfo {
+            user_agent,
+            ip_address: request.client_ip().map(|ip| ip.to_string()),
+            device_name: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Handle {
+    id: HandleID,
+    // Only a keyed hash is stored, so the token is only known right after
+    // the handle is created or when a client presents it
+    value: Option<HandleToken>,
+    created_at: DateTime<Utc>,
+    // The absolute limit; a handle also expires once it has gone unused
+    // for the configured idle TTL
+    expires_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    // When the user last entered their password, and a code from a second
+    // factor, for this handle. Sensitive routes need these to be recent.
+    authenticated_at: DateTime<Utc>,
+    mfa_at: Option<DateTime<Utc>>,
+    client: ClientInfo,
+    // Set for handles issued to OAuth clients or created with an API key,
+    // which can only do what these allow. Other handles can do everything.
+    scopes: Option<Vec<Scope>>,
+}
 
 impl Handle {
-    pub fn new(user: &Account, db: &Database) -> Result<Handle, HandleDBError> {
+    /// `expires_by` is the expiry of the handle this one is made from, if
+    /// any, which it can't outlive
+    pub fn new(
+        user: &Account,
+        authenticated_at: DateTime<Utc>,
+        mfa_at: Option<DateTime<Utc>>,
+        expires_by: Option<DateTime<Utc>>,
+        client: &ClientInfo,
+        db: &Database,
+    ) -> Result<Handle, HandleDBError> {
+        Self::create(user, authenticated_at, mfa_at, expires_by, client, None, None, db)
+    }
+
+    /// A handle limited to `scopes`, issued to the OAuth client `client_id`
+    /// if any, which revokes it when deleted. Nobody entered a password for
+    /// it, so it never counts as a recent authentication.
+    pub fn new_scoped(
+        user: &Account,
+        scopes: &[Scope],
+        client_id: Option<&str>,
+        expires_by: Option<DateTime<Utc>>,
+        client: &ClientInfo,
+        db: &Database,
+    ) -> Result<Handle, HandleDBError> {
+        Self::create(user, DateTime::UNIX_EPOCH, None, expires_by, client, Some(scopes), client_id, db)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        user: &Account,
+        authenticated_at: DateTime<Utc>,
+        mfa_at: Option<DateTime<Utc>>,
+        expires_by: Option<DateTime<Utc>>,
+        client: &ClientInfo,
+        scopes: Option<&[Scope]>,
+        client_id: Option<&str>,
+        db: &Database,
+    ) -> Result<Handle, HandleDBError> {
         loop {
-            let num = rand::random::<u64>();
-            let res = db.add_handle_to_db(user, num);
+            let token = HandleToken::generate();
+            let res = db.add_handle_to_db(user, &token, authenticated_at, mfa_at, expires_by, client, scopes, client_id);
             match res {
-                Ok(_) => return Ok(Handle(num)),
+                Ok(handle) => return Ok(handle),
                 Err(x) => match x {
                     HandleDBError::HandleAlreadyExists => continue,
                     HandleDBError::DBError(e) => return Err(HandleDBError::DBError(e)),
@@ -23,28 +193,181 @@ impl Handle {
         }
     }
 
-    pub fn get(&self) -> u64 {
-        self.0
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_db(
+        id: HandleID,
+        value: Option<HandleToken>,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        last_used_at: DateTime<Utc>,
+        authenticated_at: DateTime<Utc>,
+        mfa_at: Option<DateTime<Utc>>,
+        client: ClientInfo,
+        scopes: Option<Vec<Scope>>,
+    ) -> Self {
+        Handle {
+            id,
+            value,
+            created_at,
+            expires_at,
+            last_used_at,
+            authenticated_at,
+            mfa_at,
+            client,
+            scopes,
+        }
+    }
+
+    pub fn id(&self) -> HandleID {
+        self.id
     }
-    
-    pub fn from_value(value: u64) -> Self {
-        Handle(value)
+
+    pub fn get(&self) -> Option<&HandleToken> {
+        self.value.as_ref()
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
     }
-    
+
+    pub fn last_used_at(&self) -> DateTime<Utc> {
+        self.last_used_at
+    }
+
+    pub fn authenticated_at(&self) -> DateTime<Utc> {
+        self.authenticated_at
+    }
+
+    pub fn mfa_at(&self) -> Option<DateTime<Utc>> {
+        self.mfa_at
+    }
+
+    pub fn client(&self) -> &ClientInfo {
+        &self.client
+    }
+
+    /// `None` for handles that aren't limited to scopes
+    pub fn scopes(&self) -> Option<&[Scope]> {
+        self.scopes.as_deref()
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().map_or(true, |scopes| scopes.contains(&scope))
+    }
+
+    // Look up a handle that hasn't expired
+    pub fn find(token: &HandleToken, db: &Database) -> Result<Handle> {
+        db.get_handle(token)
+    }
+
     // Get all handles for a user
     pub fn get_all_for_user(user_id: UserID, db: &Database) -> Result<Vec<Handle>> {
-        let handle_values = db.get_handles_for_user(user_id)?;
-        let handles = handle_values.into_iter().map(Handle::from_value).collect();
-        Ok(handles)
+        db.get_handles_for_user(user_id)
+    }
+
+    // Get the account this handle was issued to
+    pub fn owner(&self, db: &Database) -> Result<Account> {
+        db.get_user_by_handle_id(self.id)
+    }
+
+    // Record that the handle was just used, which pushes back its idle expiry
+    pub fn touch(&mut self, db: &Database) -> Result<()> {
+        self.last_used_at = db.touch_handle(self.id)?;
+        Ok(())
+    }
+
+    // Record that the user just entered their password again, and a code
+    // from a second factor if `mfa` is set
+    pub fn reauthenticate(&mut self, mfa: bool, db: &Database) -> Result<()> {
+        let now = Utc::now();
+        let mfa_at = if mfa { Some(now) } else { self.mfa_at };
+        db.set_handle_authenticated(self.id, now, mfa_at)?;
+        self.authenticated_at = now;
+        self.mfa_at = mfa_at;
+        Ok(())
     }
-    
+
     // Check if a handle is owned by a user
     pub fn is_owned_by_user(&self, user_id: UserID, db: &Database) -> bool {
-        db.is_handle_owned_by_user(self.0, user_id)
+        db.is_handle_owned_by_user(self.id, user_id)
     }
-    
+
     // Delete a handle
     pub fn delete(&self, user_id: UserID, db: &Database) -> Result<bool> {
-        db.delete_handle(self.0, user_id)
+        db.delete_handle(self.id, user_id)
+    }
+}
+
+#[cfg(test)]
+impl Handle {
+    /// A handle that was just created, for tests that don't need a database
+    pub(crate) fn for_test(authenticated_at: DateTime<Utc>, scopes: Option<Vec<Scope>>) -> Self {
+        let now = Utc::now();
+        Handle::from_db(1, None, now, now, now, authenticated_at, None, ClientInfo::default(), scopes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(scopes: Option<Vec<Scope>>) -> Handle {
+        Handle::for_test(Utc::now(), scopes)
+    }
+
+    #[test]
+    fn generated_tokens_parse() {
+        let token = HandleToken::generate();
+        assert!(token.as_str().starts_with(HandleToken::PREFIX));
+        assert!(!token.is_legacy());
+        assert_eq!(HandleToken::parse(token.as_str()), Some(token));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        assert_eq!(HandleToken::parse("abl_"), None);
+        assert_eq!(HandleToken::parse("abl_c2hvcnQ"), None);
+        assert_eq!(HandleToken::parse("abl_not base64!"), None);
+        assert_eq!(HandleToken::parse("ablk_AAAA"), None);
+        assert_eq!(HandleToken::parse("-1"), None);
+        assert_eq!(HandleToken::parse(""), None);
+    }
+
+    #[test]
+    fn legacy_tokens_are_normalized() {
+        let token = HandleToken::parse("0042").unwrap();
+        assert!(token.is_legacy());
+        assert_eq!(token, HandleToken::from_legacy(42));
+        assert_eq!(token.as_str(), "42");
+    }
+
+    #[test]
+    fn tokens_deserialize_from_strings_and_numbers() {
+        let token = HandleToken::generate();
+        let json = serde_json::to_string(&token).unwrap();
+        assert_eq!(serde_json::from_str::<HandleToken>(&json).unwrap(), token);
+        assert_eq!(serde_json::from_str::<HandleToken>("42").unwrap(), HandleToken::from_legacy(42));
+        assert!(serde_json::from_str::<HandleToken>("\"abl_short\"").is_err());
+    }
+
+    #[test]
+    fn scopes_limit_handles() {
+        assert!(handle(None).has_scope(Scope::HandlesWrite));
+        let scoped = handle(Some(vec![Scope::ProfileRead]));
+        assert!(scoped.has_scope(Scope::ProfileRead));
+        assert!(!scoped.has_scope(Scope::HandlesWrite));
+        assert!(!handle(Some(Vec::new())).has_scope(Scope::ProfileRead));
+    }
+
+    #[test]
+    fn empty_device_names_are_dropped() {
+        let client = ClientInfo::default().with_device_name(Some(""));
+        assert_eq!(client.device_name, None);
+        let client = client.with_device_name(Some("phone"));
+        assert_eq!(client.device_name.as_deref(), Some("phone"));
     }
-}
\ No newline at end of file
+}
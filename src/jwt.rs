@@ -0,0 +1,238 @@
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use crate::account::UserID;
+
+/// ID of a signing key, sent as `kid` in token headers
+pub type KeyID = String;
+
+const KEY_ID_BYTES: usize = 12;
+const TOKEN_ID_BYTES: usize = 16;
+
+/// An Ed25519 key tokens are signed with. The newest one signs; older ones
+/// stay published for a while so tokens they signed can still be checked.
+pub struct SigningKey {
+    id: KeyID,
+    key_pair: Ed25519KeyPair,
+    created_at: DateTime<Utc>,
+}
+
+/// A public key as published at `/.well-known/jwks.json` (RFC 8037)
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Jwk {
+    kty: String,
+    crv: String,
+    x: String,
+    kid: KeyID,
+    alg: String,
+    #[serde(rename = "use")]
+    usage: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// `typ` of ID tokens
+pub const ID_TOKEN_TYPE: &str = "JWT";
+/// `typ` of access tokens (RFC 9068 section 2.1)
+pub const ACCESS_TOKEN_TYPE: &str = "at+jwt";
+/// `client_id` of access tokens. Only Abuelo's own clients get them, and
+/// those aren't registered, so they share one.
+pub const ACCESS_TOKEN_CLIENT_ID: &str = "abuelo";
+
+/// Claims of an access token (RFC 9068 section 2.2), which services can
+/// check against the JWKS instead of asking Abuelo about a handle on every
+/// request
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub client_id: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    pub user_id: UserID,
+    pub username: String,
+    pub premium: bool,
+}
+
+#[derive(serde::Serialize)]
+struct Header<'a> {
+    alg: &'a str,
+    typ: &'a str,
+    kid: &'a str,
+}
+
+impl SigningKey {
+    /// Loads a key from its PKCS#8 document. Returns `None` if it's invalid.
+    pub fn from_pkcs8(id: KeyID, pkcs8: &[u8], created_at: DateTime<Utc>) -> Option<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).ok()?;
+        Some(SigningKey {
+            id,
+            key_pair,
+            created_at,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn jwk(&self) -> Jwk {
+        public_jwk(self.id.clone(), self.key_pair.public_key().as_ref())
+    }
+
+    /// Encodes and signs `claims` as a compact JWT (RFC 7519) using EdDSA.
+    /// `typ` tells kinds of token apart, so one can't be passed off as
+    /// another.
+    pub fn sign<T: serde::Serialize>(&self, typ: &str, claims: &T) -> String {
+        let header = Header {
+            alg: "EdDSA",
+            typ,
+            kid: &self.id,
+        };
+        let header = serde_json::to_vec(&header).expect("headers serialize");
+        let claims = serde_json::to_vec(claims).expect("claims serialize");
+        let signing_input = format!(
+            "{}.{}",
+            Base64UrlUnpadded::encode_string(&header),
+            Base64UrlUnpadded::encode_string(&claims)
+        );
+        let signature = self.key_pair.sign(signing_input.as_bytes());
+        format!("{}.{}", signing_input, Base64UrlUnpadded::encode_string(signature.as_ref()))
+    }
+}
+
+pub fn public_jwk(id: KeyID, public_key: &[u8]) -> Jwk {
+    Jwk {
+        kty: "OKP".to_string(),
+        crv: "Ed25519".to_string(),
+        x: Base64UrlUnpadded::encode_string(public_key),
+        kid: id,
+        alg: "EdDSA".to_string(),
+        usage: "sig".to_string(),
+    }
+}
+
+/// A random `jti`, so services can tell tokens apart
+pub fn generate_token_id() -> String {
+    let mut id = [0u8; TOKEN_ID_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut id);
+    Base64UrlUnpadded::encode_string(&id)
+}
+
+/// A new key, as its ID, PKCS#8 document and public key
+pub fn generate_signing_key() -> (KeyID, Vec<u8>, Vec<u8>) {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .expect("the system RNG works");
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).expect("generated keys are valid");
+    let mut id = [0u8; KEY_ID_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut id);
+    (
+        Base64UrlUnpadded::encode_string(&id),
+        pkcs8.as_ref().to_vec(),
+        key_pair.public_key().as_ref().to_vec(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    use super::*;
+
+    fn key() -> SigningKey {
+        let (id, pkcs8, _) = generate_signing_key();
+        SigningKey::from_pkcs8(id, &pkcs8, Utc::now()).unwrap()
+    }
+
+    fn decode(part: &str) -> serde_json::Value {
+        serde_json::from_slice(&Base64UrlUnpadded::decode_vec(part).unwrap()).unwrap()
+    }
+
+    // Checks a token against a JWK the way a service would
+    fn verify(token: &str, jwk: &Jwk) -> bool {
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let public_key = Base64UrlUnpadded::decode_vec(&jwk.x).unwrap();
+        let signature = Base64UrlUnpadded::decode_vec(signature).unwrap();
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(signing_input.as_bytes(), &signature)
+            .is_ok()
+    }
+
+    fn claims() -> AccessTokenClaims {
+        AccessTokenClaims {
+            iss: "http://localhost:8000".to_string(),
+            sub: "1".to_string(),
+            aud: "http://localhost:8000".to_string(),
+            client_id: ACCESS_TOKEN_CLIENT_ID.to_string(),
+            iat: 0,
+            exp: 300,
+            jti: generate_token_id(),
+            user_id: 1,
+            username: "alice".to_string(),
+            premium: false,
+        }
+    }
+
+    #[test]
+    fn tokens_verify_against_the_jwk() {
+        let key = key();
+        let token = key.sign(ACCESS_TOKEN_TYPE, &claims());
+        assert!(verify(&token, &key.jwk()));
+        assert!(!verify(&token, &self::key().jwk()));
+    }
+
+    #[test]
+    fn header_names_the_key_and_type() {
+        let key = key();
+        let token = key.sign(ACCESS_TOKEN_TYPE, &claims());
+        let header = decode(token.split('.').next().unwrap());
+        assert_eq!(header["alg"], "EdDSA");
+        assert_eq!(header["typ"], ACCESS_TOKEN_TYPE);
+        assert_eq!(header["kid"], key.id());
+    }
+
+    #[test]
+    fn claims_round_trip() {
+        let claims = claims();
+        let token = key().sign(ACCESS_TOKEN_TYPE, &claims);
+        let decoded = decode(token.split('.').nth(1).unwrap());
+        assert_eq!(decoded["aud"], claims.aud);
+        assert_eq!(decoded["client_id"], ACCESS_TOKEN_CLIENT_ID);
+        assert_eq!(decoded["jti"], claims.jti);
+        assert_eq!(decoded["username"], "alice");
+    }
+
+    #[test]
+    fn tampered_tokens_fail() {
+        let key = key();
+        let token = key.sign(ACCESS_TOKEN_TYPE, &claims());
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = Base64UrlUnpadded::encode_string(br#"{"sub":"2"}"#);
+        parts[1] = &forged;
+        assert!(!verify(&parts.join("."), &key.jwk()));
+    }
+
+    #[test]
+    fn token_ids_are_unique() {
+        assert_ne!(generate_token_id(), generate_token_id());
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert!(SigningKey::from_pkcs8("id".to_string(), b"not a key", Utc::now()).is_none());
+    }
+}
@@ -1,14 +1,25 @@
-#[macro_use] extern crate rocket;
-
-pub mod account;
-pub mod database;
-pub mod handle;
-/// Module for handling logging functionality
-pub mod logger;
-pub mod routes;
-
-// #[cfg(test)]
-// mod tests;
-
-// Re-export what's needed for the integration tests
+pub mod account;
+pub mod api_key;
+pub mod auth;
+pub mod config;
+pub mod database;
+pub mod device_key;
+pub mod handle;
+pub mod jwt;
+/// Module for handling logging functionality
+pub mod logger;
+pub mod metrics;
+pub mod mfa;
+pub mod oauth;
+pub mod password;
+pub mod routes;
+pub mod scope;
+pub mod secret;
+pub mod tasks;
+pub mod trusted_device;
+
+// #[cfg(test)]
+// mod tests;
+
+// Re-export what's needed for the integration tests
 pub use routes::get_routes;
\ No newline at end of file
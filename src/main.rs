@@ -1,14 +1,4 @@
-use routes::get_routes;
-mod account;
-mod database;
-mod handle;
-mod logger;
-mod routes;
-pub mod mfa;
-
-use totp_rs::Secret;
-use totp_rs::TOTP;
-use totp_rs::Algorithm;
+use abuelo::{config::Config, database::Database, logger, password::Hashers, routes, secret, tasks};
 
 #[rocket::main]
 async fn main() {
@@ -22,17 +12,27 @@ async fn main() {
         },
     }
 
-    let totp = TOTP::new(
-        Algorithm::SHA1,
-        6,
-        1,
-        30,
-        Secret::Encoded("KRSXG5CTMVRXEZLUKN2XAZLSKNSWG4TFOQ".to_string()).to_bytes().unwrap(),
-    ).unwrap();
-    let token = totp.generate_current().unwrap();
-    println!("{}", token);
-
- let _ = rocket::build()
+ let rocket = rocket::build();
+ let config = match Config::from_figment(rocket.figment()) {
+     Ok(config) => config,
+     Err(e) => {
+         log::error!("Invalid abuelo config: {}", e);
+         std::process::exit(1);
+     }
+ };
+ // Load (or generate) the secret key now rather than on the first request
+ secret::server_secret(&config);
+ // Migrate before serving, so requests never see a half-migrated schema
+ if let Err(e) = Database::with_config(config.clone()).migrate() {
+     log::error!("Failed to migrate database: {}", e);
+     std::process::exit(1);
+ }
+ // Build the dummy hash up front so the first login for an unknown
+ // username isn't slower than the rest
+ Hashers::from_config(&config.password).verify_missing("");
+ let _ = rocket
+        .manage(config)
+        .attach(tasks::handle_purge())
         .mount("/", routes::get_routes())
         .launch()
         .await;
@@ -0,0 +1,64 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A monotonically increasing count, exported at `GET /metrics` in the
+/// Prometheus text format.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static HANDLES_EVICTED: Counter = Counter::new(
+    "abuelo_handles_evicted_total",
+    "Handles removed because their user went over the per-user limit",
+);
+pub static HANDLES_PURGED_EXPIRED: Counter = Counter::new(
+    "abuelo_handles_purged_expired_total",
+    "Expired handles removed by the background purge",
+);
+pub static HANDLES_PURGED_ORPHANED: Counter = Counter::new(
+    "abuelo_handles_purged_orphaned_total",
+    "Handles of deleted users removed by the background purge",
+);
+pub static HANDLE_PURGE_RUNS: Counter = Counter::new(
+    "abuelo_handle_purge_runs_total",
+    "Completed runs of the background handle purge",
+);
+
+static COUNTERS: &[&Counter] = &[
+    &HANDLES_EVICTED,
+    &HANDLES_PURGED_EXPIRED,
+    &HANDLES_PURGED_ORPHANED,
+    &HANDLE_PURGE_RUNS,
+];
+
+pub fn render() -> String {
+    let mut out = String::new();
+    for counter in COUNTERS {
+        let _ = writeln!(out, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(out, "# TYPE {} counter", counter.name);
+        let _ = writeln!(out, "{} {}", counter.name, counter.get());
+    }
+    out
+}
@@ -1,9 +1,373 @@
-use totp_rs::TOTP;
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use ring::hmac;
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, TOTP};
+
+use crate::config::MfaConfig;
+
+/// Row ID of a factor
+pub type FactorID = i64;
+
+/// 160 bits, the length RFC 4226 recommends
+const OTP_SECRET_LEN: usize = 20;
+/// Every authenticator app supports 6 digit codes and 30 second steps
+const OTP_DIGITS: u32 = 6;
+const TOTP_STEP: u64 = 30;
+const CHALLENGE_PREFIX: &str = "ablmfa_";
+const CHALLENGE_BYTES: usize = 32;
+/// Lowercase letters and digits, minus ones that are easily mixed up
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+/// Written as two groups of 5, around 50 bits
+const RECOVERY_CODE_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FactorKind {
+    /// Time-based codes (RFC 6238), e.g. an authenticator app
+    Totp,
+    /// Counter-based codes (RFC 4226), e.g. a hardware token
+    Hotp,
+}
+
+impl FactorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FactorKind::Totp => "totp",
+            FactorKind::Hotp => "hotp",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "totp" => Some(FactorKind::Totp),
+            "hotp" => Some(FactorKind::Hotp),
+            _ => None,
+        }
+    }
+}
+
+/// One of a user's second factors, named by the user ("phone", "backup
+/// yubikey", ...)
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Factor {
+    id: FactorID,
+    name: String,
+    #[serde(rename = "type")]
+    kind: FactorKind,
+    #[serde(skip)]
+    secret: Vec<u8>,
+    // Unconfirmed factors are waiting for a first code and aren't enforced
+    confirmed: bool,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    // The last accepted TOTP time step or HOTP counter. Codes are only
+    // accepted for later ones, so they can't be replayed.
+    #[serde(skip)]
+    counter: Option<i64>,
+}
+
+// Keeps the secret out of logs
+impl Debug for Factor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Factor")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("kind", &self.kind)
+            .field("confirmed", &self.confirmed)
+            .finish()
+    }
+}
+
+impl Factor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_db(
+        id: FactorID,
+        name: String,
+        kind: FactorKind,
+        secret: Vec<u8>,
+        confirmed: bool,
+        created_at: DateTime<Utc>,
+        last_used_at: Option<DateTime<Utc>>,
+        counter: Option<i64>,
+    ) -> Self {
+        Factor {
+            id,
+            name,
+            kind,
+            secret,
+            confirmed,
+            created_at,
+            last_used_at,
+            counter,
+        }
+    }
+
+    pub fn id(&self) -> FactorID {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> FactorKind {
+        self.kind
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// The TOTP time step or HOTP counter `code` was generated for, if this
+    /// factor would accept it now. TOTP codes may be up to `totp_skew` steps
+    /// off, and HOTP tokens may have been pressed up to `hotp_look_ahead`
+    /// times without logging in. Callers have to record the result, see
+    /// `Database::check_factors`.
+    pub fn matching_counter(&self, code: &str, config: &MfaConfig) -> Option<i64> {
+        let code = code.trim();
+        let candidates = match self.kind {
+            FactorKind::Totp => {
+                let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(now) => now.as_secs(),
+                    Err(e) => {
+                        log::error!("System clock is before the Unix epoch: {}", e);
+                        return None;
+                    }
+                };
+                let current = (now / TOTP_STEP) as i64;
+                let skew = config.totp_skew as i64;
+                (current - skew)..=(current + skew)
+            }
+            FactorKind::Hotp => {
+                let next = self.counter.map_or(0, |counter| counter + 1);
+                next..=(next + config.hotp_look_ahead as i64)
+            }
+        };
+        candidates
+            .filter(|candidate| self.counter.map_or(true, |last| *candidate > last))
+            .find(|candidate| {
+                let expected = hotp(&self.secret, *candidate as u64);
+                bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+            })
+    }
+}
+
+/// The second factors a user has enabled. Logging in with a password also
+/// needs a code from one of them.
+#[derive(Debug, Default)]
 pub struct Factors {
- totp: Option<TOTP>
+    // Only confirmed factors
+    factors: Vec<Factor>,
+    // Unused recovery codes left
+    recovery_codes: usize,
 }
 
 impl Factors {
- 
-}
\ No newline at end of file
+    pub fn new(factors: Vec<Factor>, recovery_codes: usize) -> Self {
+        Self {
+            factors,
+            recovery_codes,
+        }
+    }
+
+    // Whether a password alone isn't enough to log in
+    pub fn any_enabled(&self) -> bool {
+        !self.factors.is_empty()
+    }
+
+    // Types of the enabled factors, as listed in MFA challenges
+    pub fn available(&self) -> Vec<String> {
+        let mut available: Vec<String> = Vec::new();
+        for factor in &self.factors {
+            let kind = factor.kind().as_str();
+            if !available.iter().any(|k| k == kind) {
+                available.push(kind.to_string());
+            }
+        }
+        if self.recovery_codes > 0 {
+            available.push("recovery_code".to_string());
+        }
+        available
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Factor> {
+        self.factors.iter()
+    }
+
+    pub fn recovery_codes(&self) -> usize {
+        self.recovery_codes
+    }
+}
+
+/// An HOTP code (RFC 4226). TOTP codes are HOTP codes for the current time
+/// step.
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let tag = tag.as_ref();
+    let offset = (tag[tag.len() - 1] & 0xf) as usize;
+    let mut truncated = [0u8; 4];
+    truncated.copy_from_slice(&tag[offset..offset + 4]);
+    let value = u32::from_be_bytes(truncated) & 0x7fff_ffff;
+    format!("{:0width$}", value % 10u32.pow(OTP_DIGITS), width = OTP_DIGITS as usize)
+}
+
+/// The token `/user/auth` hands out in place of a handle when the user has a
+/// second factor. Like handles, only a keyed hash of it is stored.
+pub fn generate_challenge() -> String {
+    let mut bytes = [0u8; CHALLENGE_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", CHALLENGE_PREFIX, Base64UrlUnpadded::encode_string(&bytes))
+}
+
+pub fn generate_recovery_codes(count: u32) -> Vec<String> {
+    let mut rng = rand::rngs::OsRng;
+    (0..count)
+        .map(|_| {
+            let code: String = (0..RECOVERY_CODE_LEN)
+                .map(|_| {
+                    let i = rand::Rng::gen_range(&mut rng, 0..RECOVERY_CODE_ALPHABET.len());
+                    RECOVERY_CODE_ALPHABET[i] as char
+                })
+                .collect();
+            format!("{}-{}", &code[..RECOVERY_CODE_LEN / 2], &code[RECOVERY_CODE_LEN / 2..])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without the dash, whitespace or case
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+pub fn generate_otp_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; OTP_SECRET_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// What a user needs to set up a new factor: the secret in base32, for
+/// typing in by hand, and an `otpauth://` URI, usually shown as a QR code
+pub fn enrollment(kind: FactorKind, secret: &[u8], username: &str, config: &MfaConfig) -> (String, String) {
+    // Unchecked because the label is percent-encoded in the URI anyway, so
+    // usernames containing ':' are fine
+    let totp = TOTP::new_unchecked(
+        Algorithm::SHA1,
+        OTP_DIGITS as usize,
+        0,
+        TOTP_STEP,
+        secret.to_vec(),
+        Some(config.issuer.clone()),
+        username.to_string(),
+    );
+    let uri = totp.get_url();
+    let uri = match kind {
+        FactorKind::Totp => uri,
+        // totp-rs only knows TOTP, but HOTP URIs only differ in the type and
+        // the starting counter
+        FactorKind::Hotp => format!("{}&counter=0", uri.replacen("otpauth://totp/", "otpauth://hotp/", 1)),
+    };
+    (totp.get_secret_base32(), uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The secret of the test vectors in RFC 4226 appendix D
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn factor(kind: FactorKind, counter: Option<i64>) -> Factor {
+        Factor::from_db(1, "test".to_string(), kind, SECRET.to_vec(), true, Utc::now(), None, counter)
+    }
+
+    fn current_step() -> i64 {
+        (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / TOTP_STEP) as i64
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489",
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn hotp_looks_ahead() {
+        let config = MfaConfig::default();
+        let factor = factor(FactorKind::Hotp, Some(2));
+        assert_eq!(factor.matching_counter(&hotp(SECRET, 3), &config), Some(3));
+        assert_eq!(factor.matching_counter(&hotp(SECRET, 13), &config), Some(13));
+        assert_eq!(factor.matching_counter(&hotp(SECRET, 14), &config), None);
+        // Codes are trimmed, as they are often pasted
+        assert_eq!(factor.matching_counter(&format!(" {} ", hotp(SECRET, 5)), &config), Some(5));
+    }
+
+    #[test]
+    fn hotp_codes_cannot_be_replayed() {
+        let config = MfaConfig::default();
+        let factor = factor(FactorKind::Hotp, Some(2));
+        assert_eq!(factor.matching_counter(&hotp(SECRET, 2), &config), None);
+        assert_eq!(factor.matching_counter(&hotp(SECRET, 1), &config), None);
+    }
+
+    #[test]
+    fn new_hotp_factors_start_at_zero() {
+        let config = MfaConfig::default();
+        let factor = factor(FactorKind::Hotp, None);
+        assert_eq!(factor.matching_counter(&hotp(SECRET, 0), &config), Some(0));
+    }
+
+    #[test]
+    fn totp_allows_skew() {
+        let config = MfaConfig::default();
+        let factor = factor(FactorKind::Totp, None);
+        let step = current_step();
+        // Even if the step changes mid-test, these stay within the window
+        assert!(factor.matching_counter(&hotp(SECRET, step as u64), &config).is_some());
+        assert!(factor.matching_counter(&hotp(SECRET, step as u64 + 1), &config).is_some());
+        assert_eq!(factor.matching_counter(&hotp(SECRET, step as u64 + 3), &config), None);
+        assert_eq!(factor.matching_counter(&hotp(SECRET, step as u64 - 3), &config), None);
+    }
+
+    #[test]
+    fn totp_codes_cannot_be_replayed() {
+        let config = MfaConfig::default();
+        let step = current_step();
+        let factor = factor(FactorKind::Totp, Some(step + 1));
+        assert_eq!(factor.matching_counter(&hotp(SECRET, step as u64), &config), None);
+        assert_eq!(factor.matching_counter(&hotp(SECRET, step as u64 + 1), &config), None);
+    }
+
+    #[test]
+    fn recovery_codes_normalize() {
+        let codes = generate_recovery_codes(3);
+        assert_eq!(codes.len(), 3);
+        for code in codes {
+            assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+            assert_eq!(normalize_recovery_code(&code.to_uppercase()), code.replace('-', ""));
+        }
+    }
+
+    #[test]
+    fn hotp_enrollment_uri() {
+        let config = MfaConfig::default();
+        let (secret, uri) = enrollment(FactorKind::Hotp, SECRET, "alice", &config);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert!(uri.starts_with("otpauth://hotp/"));
+        assert!(uri.ends_with("&counter=0"));
+    }
+}
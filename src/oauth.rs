@@ -0,0 +1,301 @@
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    account::{Account, UserID},
+    scope::Scope,
+};
+
+const DEVICE_CODE_PREFIX: &str = "abldc_";
+const DEVICE_CODE_BYTES: usize = 32;
+/// Consonants only, so codes can't spell words, as RFC 8628 section 6.1
+/// suggests. 8 of them are around 34 bits.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LEN: usize = 8;
+
+/// `grant_type` for polling with a device code (RFC 8628 section 3.4)
+pub const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The error body of OAuth endpoints (RFC 6749 section 5.2), sent with a
+/// 400 status
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct OAuthError {
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+}
+
+impl OAuthError {
+    pub fn new(error: &str) -> Self {
+        OAuthError {
+            error: error.to_string(),
+            error_description: None,
+        }
+    }
+
+    pub fn with_description(error: &str, description: &str) -> Self {
+        OAuthError {
+            error: error.to_string(),
+            error_description: Some(description.to_string()),
+        }
+    }
+}
+
+/// What a device polling with its device code is told
+#[derive(Debug)]
+pub enum DeviceGrantPoll {
+    /// The user hasn't approved or denied the code yet
+    Pending,
+    /// The device is polling faster than its interval, which has now grown
+    SlowDown,
+    Denied,
+    Expired,
+    /// The user approved the code. It has been used up, so this is only
+    /// returned once.
+    Approved(Account),
+    /// No such device code, or it was already used
+    Invalid,
+}
+
+/// The secret a device polls with. Like handles, only a keyed hash of it is
+/// stored.
+pub fn generate_device_code() -> String {
+    random_token(DEVICE_CODE_PREFIX, DEVICE_CODE_BYTES)
+}
+
+/// The code the user types in to approve a device, stored normalized
+pub fn generate_user_code() -> String {
+    let mut rng = rand::rngs::OsRng;
+    (0..USER_CODE_LEN)
+        .map(|_| {
+            let i = rand::Rng::gen_range(&mut rng, 0..USER_CODE_ALPHABET.len());
+            USER_CODE_ALPHABET[i] as char
+        })
+        .collect()
+}
+
+/// Shown to the user as two groups of 4, e.g. `WDJB-MJHT`
+pub fn format_user_code(code: &str) -> String {
+    format!("{}-{}", &code[..USER_CODE_LEN / 2], &code[USER_CODE_LEN / 2..])
+}
+
+/// User codes are compared without the dash, whitespace or case
+pub fn normalize_user_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+const CLIENT_ID_PREFIX: &str = "ablc_";
+const CLIENT_ID_BYTES: usize = 16;
+const CLIENT_SECRET_PREFIX: &str = "ablcs_";
+const CLIENT_SECRET_BYTES: usize = 32;
+const AUTHORIZATION_CODE_PREFIX: &str = "ablac_";
+const AUTHORIZATION_CODE_BYTES: usize = 32;
+
+/// `grant_type` for redeeming an authorization code (RFC 6749 section 4.1.3)
+pub const AUTHORIZATION_CODE_GRANT: &str = "authorization_code";
+
+/// A service that lets users log in with Abuelo, registered by one of them.
+/// Confidential clients also authenticate with a secret; public ones, such
+/// as native apps, only have PKCE.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OAuthClient {
+    client_id: String,
+    name: String,
+    redirect_uris: Vec<String>,
+    confidential: bool,
+    created_at: DateTime<Utc>,
+}
+
+impl OAuthClient {
+    pub fn from_db(
+        client_id: String,
+        name: String,
+        redirect_uris: Vec<String>,
+        confidential: bool,
+        created_at: DateTime<Utc>,
+    ) -> Self {
+        OAuthClient {
+            client_id,
+            name,
+            redirect_uris,
+            confidential,
+            created_at,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_confidential(&self) -> bool {
+        self.confidential
+    }
+
+    /// Redirect URIs have to match a registered one exactly
+    pub fn allows_redirect_uri(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|uri| uri == redirect_uri)
+    }
+}
+
+/// What an authorization code was issued for, checked again when it is
+/// redeemed
+#[derive(Debug)]
+pub struct AuthorizationGrant {
+    pub client_id: String,
+    pub user_id: UserID,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    // When the user last entered their password for the handle that
+    // approved the client, if they ever did
+    pub auth_time: Option<DateTime<Utc>>,
+}
+
+/// What the handle issued for an authorization grant may do. It's only
+/// ever good for `/oauth/userinfo`, and only with the `profile` scope;
+/// `openid` alone just gets the client an ID token.
+pub fn token_scopes(scope: &str) -> Vec<Scope> {
+    scope
+        .split_whitespace()
+        .filter_map(|scope| match scope {
+            "profile" => Some(Scope::ProfileRead),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Claims of an OpenID Connect ID token
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub preferred_username: String,
+    pub premium: bool,
+}
+
+fn random_token(prefix: &str, len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", prefix, Base64UrlUnpadded::encode_string(&bytes))
+}
+
+pub fn generate_client_id() -> String {
+    random_token(CLIENT_ID_PREFIX, CLIENT_ID_BYTES)
+}
+
+/// Only a keyed hash of client secrets is stored, like handles
+pub fn generate_client_secret() -> String {
+    random_token(CLIENT_SECRET_PREFIX, CLIENT_SECRET_BYTES)
+}
+
+pub fn generate_authorization_code() -> String {
+    random_token(AUTHORIZATION_CODE_PREFIX, AUTHORIZATION_CODE_BYTES)
+}
+
+/// Redirect URIs have to be absolute and can't have a fragment (RFC 6749
+/// section 3.1.2). Custom schemes are allowed for native apps.
+pub fn is_valid_redirect_uri(redirect_uri: &str) -> bool {
+    url::Url::parse(redirect_uri).is_ok_and(|uri| uri.fragment().is_none() && !uri.cannot_be_a_base())
+}
+
+/// Adds parameters to the query of a redirect URI. It was checked when the
+/// client was registered, so it parses.
+pub fn redirect_with(redirect_uri: &str, params: &[(&str, &str)]) -> String {
+    let mut uri = url::Url::parse(redirect_uri).expect("registered redirect URIs are valid");
+    uri.query_pairs_mut().extend_pairs(params);
+    uri.to_string()
+}
+
+/// Checks a PKCE code verifier against the S256 challenge it was made for
+/// (RFC 7636 section 4.6)
+pub fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    let valid_verifier = (43..=128).contains(&code_verifier.len())
+        && code_verifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+    if !valid_verifier {
+        return false;
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, code_verifier.as_bytes());
+    let expected = Base64UrlUnpadded::encode_string(digest.as_ref());
+    bool::from(expected.as_bytes().ct_eq(code_challenge.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERIFIER: &str = "dBjftJeZ4CVP-mJ92K1ZuqqHHBvNTcvTPxvLebsPoTQ";
+    const CHALLENGE: &str = "4jG_pV_3Ueg9cDTXjCD2GjXnnMYn6LjhktESu1Q6n6I";
+
+    #[test]
+    fn pkce_verifies() {
+        assert!(verify_pkce(VERIFIER, CHALLENGE));
+    }
+
+    #[test]
+    fn pkce_rejects_other_verifiers() {
+        assert!(!verify_pkce(&VERIFIER.replace('d', "e"), CHALLENGE));
+        // The plain method isn't supported
+        assert!(!verify_pkce(VERIFIER, VERIFIER));
+    }
+
+    fn challenge(verifier: &str) -> String {
+        Base64UrlUnpadded::encode_string(ring::digest::digest(&ring::digest::SHA256, verifier.as_bytes()).as_ref())
+    }
+
+    #[test]
+    fn pkce_rejects_malformed_verifiers() {
+        let valid = "a".repeat(43);
+        assert!(verify_pkce(&valid, &challenge(&valid)));
+        for verifier in ["a".repeat(42), "a".repeat(129), format!("{}+", valid)] {
+            assert!(!verify_pkce(&verifier, &challenge(&verifier)), "{}", verifier);
+        }
+    }
+
+    #[test]
+    fn user_codes_normalize() {
+        let code = generate_user_code();
+        assert_eq!(code.len(), USER_CODE_LEN);
+        let formatted = format_user_code(&code);
+        assert_eq!(formatted.len(), USER_CODE_LEN + 1);
+        assert_eq!(normalize_user_code(&format!(" {} ", formatted.to_lowercase())), code);
+    }
+
+    #[test]
+    fn redirect_uris() {
+        assert!(is_valid_redirect_uri("https://wiki.example/cb"));
+        assert!(is_valid_redirect_uri("com.example.app:/cb"));
+        assert!(!is_valid_redirect_uri("https://wiki.example/cb#fragment"));
+        assert!(!is_valid_redirect_uri("/cb"));
+        assert!(!is_valid_redirect_uri("mailto:someone@example.com"));
+        assert_eq!(
+            redirect_with("https://wiki.example/cb?a=1", &[("code", "x y"), ("state", "s")]),
+            "https://wiki.example/cb?a=1&code=x+y&state=s"
+        );
+    }
+
+    #[test]
+    fn only_profile_grants_a_scope() {
+        assert_eq!(token_scopes("openid profile"), vec![Scope::ProfileRead]);
+        assert!(token_scopes("openid").is_empty());
+        assert!(token_scopes("").is_empty());
+    }
+}
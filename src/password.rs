@@ -0,0 +1,566 @@
+use std::{num::NonZeroU32, sync::OnceLock};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use base64ct::{Base64Bcrypt, Base64Unpadded, Encoding};
+use rand::RngCore;
+use ring::pbkdf2;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::config::PasswordConfig;
+
+/// Length of the per-user salts generated for new passwords
+pub const SALT_LEN: usize = 16;
+
+/// A password hashing scheme.
+///
+/// Every hash is stored as a self-describing string that starts with
+/// `$<identifier>$`, so rows produced by different hashers can live side by
+/// side and be verified by whichever hasher recognizes them. The salt is
+/// kept in its own column and handed to the hasher; formats that embed a
+/// salt carry a copy of it.
+pub trait PasswordHasher: Send + Sync {
+    /// Name of the hasher in the config, and the identifier it writes into
+    /// the hashes it produces.
+    fn id(&self) -> &'static str;
+
+    /// Whether this hasher can verify hashes with the given identifier
+    fn recognizes(&self, id: &str) -> bool {
+        id == self.id()
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> String;
+
+    fn verify(&self, password: &str, salt: &[u8], hash: &str) -> bool;
+
+    /// Whether a hash that verified should be replaced because it was
+    /// produced with weaker settings than the current ones
+    fn needs_rehash(&self, _hash: &str) -> bool {
+        false
+    }
+}
+
+/// Result of checking a password against a stored hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Invalid,
+    Valid,
+    /// The password matched, but the stored hash was produced by a hasher
+    /// other than the configured one, or with outdated settings
+    Outdated,
+}
+
+/// The configured hasher for new passwords, plus every hasher that existing
+/// hashes may be verified with.
+pub struct Hashers {
+    primary: Box<dyn PasswordHasher>,
+    accepted: Vec<Box<dyn PasswordHasher>>,
+}
+
+impl Hashers {
+    pub fn from_config(config: &PasswordConfig) -> Self {
+        let primary = build_hasher(&config.algorithm, config).unwrap_or_else(|| {
+            log::error!(
+                "Unknown password hashing algorithm {}, falling back to argon2id",
+                config.algorithm
+            );
+            Box::new(Argon2idHasher::from_config(config))
+        });
+        let accepted = config
+            .accepted
+            .iter()
+            .filter(|name| **name != primary.id())
+            .filter_map(|name| {
+                let hasher = build_hasher(name, config);
+                if hasher.is_none() {
+                    log::error!("Unknown password hashing algorithm {} in accepted list", name);
+                }
+                hasher
+            })
+            .collect();
+        Self { primary, accepted }
+    }
+
+    pub fn hash(&self, password: &str, salt: &[u8]) -> String {
+        self.primary.hash(password, salt)
+    }
+
+    pub fn verify(&self, password: &str, salt: &[u8], hash: &str) -> PasswordCheck {
+        let Some(id) = hash_id(hash) else {
+            log::error!("Stored password hash has no identifier");
+            return PasswordCheck::Invalid;
+        };
+
+        if self.primary.recognizes(id) {
+            return match self.primary.verify(password, salt, hash) {
+                false => PasswordCheck::Invalid,
+                true if self.primary.needs_rehash(hash) => PasswordCheck::Outdated,
+                true => PasswordCheck::Valid,
+            };
+        }
+        match self.accepted.iter().find(|hasher| hasher.recognizes(id)) {
+            Some(hasher) if hasher.verify(password, salt, hash) => PasswordCheck::Outdated,
+            Some(_) => PasswordCheck::Invalid,
+            None => {
+                log::warn!("Password hash uses {}, which is not an accepted algorithm", id);
+                PasswordCheck::Invalid
+            }
+        }
+    }
+
+    /// Takes as long as verifying against a real hash, so logins for
+    /// usernames that don't exist can't be told apart by timing. Always
+    /// `Invalid`.
+    pub fn verify_missing(&self, password: &str) -> PasswordCheck {
+        // The config can't change while the process runs, so one dummy
+        // from the primary hasher is enough
+        static DUMMY: OnceLock<(Vec<u8>, String)> = OnceLock::new();
+        let (salt, hash) = DUMMY.get_or_init(|| {
+            let salt = generate_salt();
+            let hash = self.primary.hash("", &salt);
+            (salt, hash)
+        });
+        let _ = self.primary.verify(password, salt, hash);
+        PasswordCheck::Invalid
+    }
+}
+
+fn build_hasher(name: &str, config: &PasswordConfig) -> Option<Box<dyn PasswordHasher>> {
+    let hasher: Box<dyn PasswordHasher> = match name {
+        "argon2id" => Box::new(Argon2idHasher::from_config(config)),
+        "pbkdf2-sha256" => Box::new(Pbkdf2Sha256Hasher::from_config(config)),
+        "scrypt" => Box::new(ScryptHasher),
+        "bcrypt" => Box::new(BcryptHasher::from_config(config)),
+        "abuelo-sha256" => Box::new(LegacySha256Hasher),
+        _ => return None,
+    };
+    Some(hasher)
+}
+
+/// The identifier between the first two `$` of a stored hash
+fn hash_id(hash: &str) -> Option<&str> {
+    hash.strip_prefix('$')?.split('$').next()
+}
+
+/// A fresh salt from the OS CSPRNG
+pub fn generate_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// The salt embedded in a stored hash, if it has one: PHC-style strings,
+/// which includes `abuelo-sha256`, and bcrypt's own format
+pub fn embedded_salt(hash: &str) -> Option<Vec<u8>> {
+    if hash_id(hash).is_some_and(|id| BCRYPT_IDS.contains(&id)) {
+        // `$2b$<cost>$` followed by 22 characters of salt
+        let salt = hash.get(7..29)?;
+        return Base64Bcrypt::decode_vec(salt).ok();
+    }
+    let mut buf = [0u8; 64];
+    let salt = PasswordHash::new(hash).ok()?.salt?;
+    salt.decode_b64(&mut buf).ok().map(<[u8]>::to_vec)
+}
+
+/// Verifies a PHC hash with `salt` substituted for the embedded one, so the
+/// salt column stays the source of truth
+fn verify_phc(verifier: &impl PasswordVerifier, password: &str, salt: &[u8], hash: &str) -> bool {
+    let (Ok(mut parsed), Ok(salt)) = (PasswordHash::new(hash), SaltString::encode_b64(salt)) else {
+        return false;
+    };
+    parsed.salt = Some(salt.as_salt());
+    verifier.verify_password(password.as_bytes(), &parsed).is_ok()
+}
+
+// ARGON2ID ----------------------------------------------------------------
+
+/// `$argon2id$v=19$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<hash>`
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    pub fn from_config(config: &PasswordConfig) -> Self {
+        let params = Params::new(config.memory_cost, config.time_cost, config.parallelism, None)
+            .unwrap_or_else(|e| {
+                log::error!("Invalid argon2id parameters, using defaults: {}", e);
+                Params::default()
+            });
+        Self { params }
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn id(&self) -> &'static str {
+        "argon2id"
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        let salt = SaltString::encode_b64(salt).expect("salt length is valid");
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("Argon2 output length is always valid")
+            .to_string()
+    }
+
+    fn verify(&self, password: &str, salt: &[u8], hash: &str) -> bool {
+        // Verification uses the parameters recorded in the hash itself
+        verify_phc(&Argon2::default(), password, salt, hash)
+    }
+
+    fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        !Params::try_from(&parsed).is_ok_and(|params| {
+            params.m_cost() == self.params.m_cost()
+                && params.t_cost() == self.params.t_cost()
+                && params.p_cost() == self.params.p_cost()
+        })
+    }
+}
+
+// PBKDF2 ------------------------------------------------------------------
+
+/// `$pbkdf2-sha256$i=<iterations>$<salt>$<hash>`
+pub struct Pbkdf2Sha256Hasher {
+    iterations: NonZeroU32,
+}
+
+impl Pbkdf2Sha256Hasher {
+    pub fn from_config(config: &PasswordConfig) -> Self {
+        let iterations = NonZeroU32::new(config.pbkdf2_iterations).unwrap_or_else(|| {
+            log::error!("pbkdf2_iterations must not be zero, using 600000");
+            NonZeroU32::new(600_000).unwrap()
+        });
+        Self { iterations }
+    }
+}
+
+impl PasswordHasher for Pbkdf2Sha256Hasher {
+    fn id(&self) -> &'static str {
+        "pbkdf2-sha256"
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        let mut out = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            salt,
+            password.as_bytes(),
+            &mut out,
+        );
+        format!(
+            "${}$i={}${}${}",
+            self.id(),
+            self.iterations,
+            Base64Unpadded::encode_string(salt),
+            Base64Unpadded::encode_string(&out)
+        )
+    }
+
+    fn verify(&self, password: &str, salt: &[u8], hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return false;
+        };
+        let iterations = parsed
+            .params
+            .get_decimal("i")
+            .and_then(NonZeroU32::new);
+        match (iterations, parsed.hash) {
+            (Some(iterations), Some(expected)) => pbkdf2::verify(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                iterations,
+                salt,
+                password.as_bytes(),
+                expected.as_bytes(),
+            )
+            .is_ok(),
+            _ => false,
+        }
+    }
+
+    fn needs_rehash(&self, hash: &str) -> bool {
+        PasswordHash::new(hash)
+            .ok()
+            .and_then(|parsed| parsed.params.get_decimal("i"))
+            .map_or(true, |iterations| iterations < self.iterations.get())
+    }
+}
+
+// SCRYPT ------------------------------------------------------------------
+
+/// `$scrypt$ln=<log2 N>,r=<r>,p=<p>$<salt>$<hash>`, as written by the
+/// RustCrypto and passlib implementations
+pub struct ScryptHasher;
+
+impl PasswordHasher for ScryptHasher {
+    fn id(&self) -> &'static str {
+        "scrypt"
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        let salt = SaltString::encode_b64(salt).expect("salt length is valid");
+        scrypt::Scrypt
+            .hash_password(password.as_bytes(), &salt)
+            .expect("scrypt output length is always valid")
+            .to_string()
+    }
+
+    fn verify(&self, password: &str, salt: &[u8], hash: &str) -> bool {
+        verify_phc(&scrypt::Scrypt, password, salt, hash)
+    }
+}
+
+// BCRYPT ------------------------------------------------------------------
+
+/// `$2b$<cost>$<salt><hash>`, the modular crypt format used by most bcrypt
+/// implementations. Imported bcrypt hashes are verified with the salt
+/// inside them, since bcrypt encodes it in its own base64 dialect.
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+/// Costs the bcrypt crate accepts
+const BCRYPT_COSTS: std::ops::RangeInclusive<u32> = 4..=31;
+const BCRYPT_SALT_LEN: usize = 16;
+/// Version prefixes of the bcrypt variants, all verified the same way
+const BCRYPT_IDS: &[&str] = &["2a", "2b", "2x", "2y"];
+
+impl BcryptHasher {
+    pub fn from_config(config: &PasswordConfig) -> Self {
+        let cost = if BCRYPT_COSTS.contains(&config.bcrypt_cost) {
+            config.bcrypt_cost
+        } else {
+            log::error!(
+                "bcrypt_cost must be between 4 and 31, using {}",
+                bcrypt::DEFAULT_COST
+            );
+            bcrypt::DEFAULT_COST
+        };
+        Self { cost }
+    }
+
+    /// bcrypt takes exactly 16 bytes of salt. Salts of any other length
+    /// are hashed down to that.
+    fn salt(salt: &[u8]) -> [u8; BCRYPT_SALT_LEN] {
+        let mut bcrypt_salt = [0u8; BCRYPT_SALT_LEN];
+        if salt.len() == BCRYPT_SALT_LEN {
+            bcrypt_salt.copy_from_slice(salt);
+        } else {
+            let digest = ring::digest::digest(&ring::digest::SHA256, salt);
+            bcrypt_salt.copy_from_slice(&digest.as_ref()[..BCRYPT_SALT_LEN]);
+        }
+        bcrypt_salt
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn id(&self) -> &'static str {
+        "bcrypt"
+    }
+
+    fn recognizes(&self, id: &str) -> bool {
+        BCRYPT_IDS.contains(&id)
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        bcrypt::hash_with_salt(password, self.cost, Self::salt(salt))
+            .expect("the cost was checked in from_config")
+            .format_for_version(bcrypt::Version::TwoB)
+    }
+
+    fn verify(&self, password: &str, _salt: &[u8], hash: &str) -> bool {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+
+    fn needs_rehash(&self, hash: &str) -> bool {
+        hash.split('$')
+            .nth(2)
+            .and_then(|cost| cost.parse::<u32>().ok())
+            .map_or(true, |cost| cost < self.cost)
+    }
+}
+
+// LEGACY SHA-256 ----------------------------------------------------------
+
+/// The original scheme: one round of SHA-256 over the password followed by
+/// the salt. Old rows were salted with the creation minute and
+/// `random_value`, which `Database` migrates into the salt column.
+///
+/// `$abuelo-sha256$<salt>$<hash>`
+pub struct LegacySha256Hasher;
+
+impl LegacySha256Hasher {
+    pub fn encode(salt: &[u8], digest: &[u8]) -> String {
+        format!(
+            "$abuelo-sha256${}${}",
+            Base64Unpadded::encode_string(salt),
+            Base64Unpadded::encode_string(digest)
+        )
+    }
+
+    fn digest(password: &str, salt: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(password);
+        hasher.update(salt);
+        hasher.finalize().to_vec()
+    }
+}
+
+impl PasswordHasher for LegacySha256Hasher {
+    fn id(&self) -> &'static str {
+        "abuelo-sha256"
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> String {
+        Self::encode(salt, &Self::digest(password, salt))
+    }
+
+    fn verify(&self, password: &str, salt: &[u8], hash: &str) -> bool {
+        let mut parts = hash.split('$').skip(3);
+        let (Some(expected), None) = (parts.next(), parts.next()) else {
+            return false;
+        };
+        Base64Unpadded::decode_vec(expected)
+            .is_ok_and(|expected| Self::digest(password, salt).ct_eq(&expected).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap enough to hash many times in tests
+    fn config(algorithm: &str, accepted: &[&str]) -> PasswordConfig {
+        PasswordConfig {
+            algorithm: algorithm.to_string(),
+            accepted: accepted.iter().map(|name| name.to_string()).collect(),
+            memory_cost: 64,
+            time_cost: 1,
+            parallelism: 1,
+            pbkdf2_iterations: 1000,
+            bcrypt_cost: 4,
+        }
+    }
+
+    #[test]
+    fn primary_hash_verifies() {
+        let hashers = Hashers::from_config(&config("argon2id", &[]));
+        let salt = generate_salt();
+        let hash = hashers.hash("hunter2", &salt);
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(hashers.verify("hunter2", &salt, &hash), PasswordCheck::Valid);
+        assert_eq!(hashers.verify("hunter3", &salt, &hash), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn salt_column_is_used() {
+        let hashers = Hashers::from_config(&config("argon2id", &[]));
+        let hash = hashers.hash("hunter2", &generate_salt());
+        assert_eq!(hashers.verify("hunter2", &generate_salt(), &hash), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn accepted_hashes_are_outdated() {
+        let salt = generate_salt();
+        let legacy = LegacySha256Hasher.hash("hunter2", &salt);
+        let hashers = Hashers::from_config(&config("argon2id", &["abuelo-sha256"]));
+        assert_eq!(hashers.verify("hunter2", &salt, &legacy), PasswordCheck::Outdated);
+        assert_eq!(hashers.verify("hunter3", &salt, &legacy), PasswordCheck::Invalid);
+
+        let hashers = Hashers::from_config(&config("argon2id", &[]));
+        assert_eq!(hashers.verify("hunter2", &salt, &legacy), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn weaker_settings_are_outdated() {
+        let salt = generate_salt();
+        let argon2 = Hashers::from_config(&config("argon2id", &[])).hash("hunter2", &salt);
+        let stronger = PasswordConfig {
+            time_cost: 2,
+            ..config("argon2id", &[])
+        };
+        assert_eq!(
+            Hashers::from_config(&stronger).verify("hunter2", &salt, &argon2),
+            PasswordCheck::Outdated
+        );
+
+        let pbkdf2 = Hashers::from_config(&config("pbkdf2-sha256", &[])).hash("hunter2", &salt);
+        let stronger = PasswordConfig {
+            pbkdf2_iterations: 2000,
+            ..config("pbkdf2-sha256", &[])
+        };
+        assert_eq!(
+            Hashers::from_config(&stronger).verify("hunter2", &salt, &pbkdf2),
+            PasswordCheck::Outdated
+        );
+
+        let bcrypt = Hashers::from_config(&config("bcrypt", &[])).hash("hunter2", &salt);
+        let stronger = PasswordConfig {
+            bcrypt_cost: 5,
+            ..config("bcrypt", &[])
+        };
+        assert_eq!(
+            Hashers::from_config(&stronger).verify("hunter2", &salt, &bcrypt),
+            PasswordCheck::Outdated
+        );
+    }
+
+    #[test]
+    fn every_hasher_round_trips() {
+        let config = config("argon2id", &[]);
+        let salt = generate_salt();
+        for name in ["argon2id", "pbkdf2-sha256", "bcrypt", "abuelo-sha256"] {
+            let hasher = build_hasher(name, &config).unwrap();
+            let hash = hasher.hash("hunter2", &salt);
+            assert!(hasher.recognizes(hash_id(&hash).unwrap()), "{}", name);
+            assert!(hasher.verify("hunter2", &salt, &hash), "{}", name);
+            assert!(!hasher.verify("hunter3", &salt, &hash), "{}", name);
+        }
+    }
+
+    #[test]
+    fn embedded_salts() {
+        let salt = generate_salt();
+        let config = config("argon2id", &[]);
+        for name in ["argon2id", "pbkdf2-sha256", "bcrypt", "abuelo-sha256"] {
+            let hash = build_hasher(name, &config).unwrap().hash("hunter2", &salt);
+            assert_eq!(embedded_salt(&hash), Some(salt.clone()), "{}", name);
+        }
+        // Imported from elsewhere, e.g. PHP's password_hash
+        let imported = bcrypt::hash_with_salt("hunter2", 4, [7u8; 16]).unwrap().to_string();
+        assert_eq!(embedded_salt(&imported), Some(vec![7u8; 16]));
+        assert_eq!(embedded_salt("not a hash"), None);
+    }
+
+    #[test]
+    fn unknown_algorithm_falls_back_to_argon2id() {
+        let hashers = Hashers::from_config(&config("md5", &["rot13"]));
+        assert_eq!(hashers.primary.id(), "argon2id");
+        assert!(hashers.accepted.is_empty());
+    }
+
+    #[test]
+    fn invalid_bcrypt_cost_falls_back() {
+        let hasher = BcryptHasher::from_config(&PasswordConfig {
+            bcrypt_cost: 99,
+            ..config("bcrypt", &[])
+        });
+        assert_eq!(hasher.cost, bcrypt::DEFAULT_COST);
+    }
+
+    #[test]
+    fn missing_users_never_verify() {
+        let hashers = Hashers::from_config(&config("argon2id", &[]));
+        assert_eq!(hashers.verify_missing(""), PasswordCheck::Invalid);
+    }
+}
@@ -1,36 +1,131 @@
 use chrono::{DateTime, Utc};
-use rocket::post;
+use rocket::{
+    form::Form,
+    get,
+    http::{uri::Origin, Status},
+    post,
+    response::Redirect,
+    serde::json::Json,
+    FromForm, Route,
+};
+
+use crate::{
+    account::{Account, UserID},
+    api_key::{self, ApiKey, ApiKeyID},
+    auth::{AuthError, AuthenticatedUser, ClientCredentials, RecentlyAuthenticatedUser},
+    database::{Database, DeviceKeyDBError, FactorDBError},
+    device_key::{self, DeviceKey, DeviceKeyID},
+    handle::{ClientInfo, Handle, HandleID, HandleToken},
+    jwt::{self, AccessTokenClaims, JwkSet},
+    metrics,
+    mfa::{self, Factor, FactorID, FactorKind},
+    oauth::{self, AuthorizationGrant, DeviceGrantPoll, IdTokenClaims, OAuthClient, OAuthError},
+    scope::{self, Scope},
+    trusted_device::{TrustedDevice, TrustedDeviceID},
+};
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct UserHandlesResponse {
     success: bool,
     message: String,
-    handles: Option<Vec<u64>>,
+    handles: Option<Vec<HandleID>>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+/// Username and password are only needed when no bearer handle is sent
+#[derive(serde::Serialize, serde::Deserialize, Default)]
 pub struct HandleRequest {
-    username: String,
-    password: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    // Shown when listing sessions
+    #[serde(default)]
+    device_name: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct HandleResponse {
     success: bool,
     message: String,
-    handle: Option<u64>,
+    handle: Option<HandleToken>,
+    expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct DeleteHandleRequest {
-    username: String,
-    password: String,
-    handle: u64,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    handle: HandleToken,
+}
+
+/// When the caller last entered their password, and a code from a second
+/// factor
+type AuthenticatedAt = (DateTime<Utc>, Option<DateTime<Utc>>);
+
+/// Works out who is calling a route that changes an account, and when they
+/// authenticated. A bearer handle is preferred; a username and password in
+/// the body are still accepted until clients have moved over. API keys never
+/// count as having authenticated recently.
+fn authenticate(
+    db: &Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<(Account, AuthenticatedAt), String> {
+    match auth {
+        Ok(user) => {
+            let authenticated_at = match user.handle() {
+                Some(handle) => (handle.authenticated_at(), handle.mfa_at()),
+                None => (DateTime::UNIX_EPOCH, None),
+            };
+            return Ok((user.into_account(), authenticated_at));
+        }
+        Err(AuthError::Missing) => {}
+        Err(err) => return Err(err.to_string()),
+    }
+
+    let (Some(username), Some(password)) = (username, password) else {
+        return Err(AuthError::Missing.to_string());
+    };
+    log::warn!("User {} sent a password instead of a bearer handle, which is deprecated", username);
+    if !db.check_login(username, password) {
+        log::warn!("Authentication failed for user: {}", username);
+        return Err("Invalid username or password".to_string());
+    }
+    let user = db.get_user(username).map_err(|err| {
+        log::error!("User not found after authenticating: {}, error: {:?}", username, err);
+        "User not found".to_string()
+    })?;
+    // There's no code in these requests, so a password alone mustn't do
+    if user.factors().any_enabled() {
+        return Err("Accounts with a second factor must use a bearer handle".to_string());
+    }
+    Ok((user, (Utc::now(), None)))
+}
+
+/// Checks a code from one of the user's factors, or one of their recovery
+/// codes, which is then burnt
+fn check_second_factor(db: &Database, user: &Account, code: &str) -> bool {
+    db.check_factors(user, code)
+        || match db.use_recovery_code(user.id(), code) {
+            Ok(used) => {
+                if used {
+                    log::info!("User {} used a recovery code", user.username());
+                }
+                used
+            },
+            Err(err) => {
+                log::error!("Error checking recovery code for user {}: {:?}", user.username(), err);
+                false
+            },
+        }
 }
 
 #[get("/user/<username>/handles")]
-fn get_user_handles(username: String) -> Json<UserHandlesResponse> {
+fn get_user_handles(db: Database, username: String) -> Json<UserHandlesResponse> {
     log::info!("Getting handles for user: {}", username);
-    let db = Database::new();
     
     let user_result = db.get_user(&username);
     if user_result.is_err() {
@@ -48,12 +143,12 @@ fn get_user_handles(username: String) -> Json<UserHandlesResponse> {
     
     match handles_result {
         Ok(handles) => {
-            let handle_values: Vec<u64> = handles.iter().map(|h| h.get()).collect();
-            log::info!("Successfully retrieved {} handles for user: {}", handle_values.len(), username);
+            let handle_ids: Vec<HandleID> = handles.iter().map(|h| h.id()).collect();
+            log::info!("Successfully retrieved {} handles for user: {}", handle_ids.len(), username);
             Json(UserHandlesResponse {
                 success: true,
                 message: "".to_string(),
-                handles: Some(handle_values),
+                handles: Some(handle_ids),
             })
         },
         Err(err) => {
@@ -68,127 +163,186 @@ fn get_user_handles(username: String) -> Json<UserHandlesResponse> {
 }
 
 #[post("/user/handle/create", data = "<body>")]
-fn create_new_handle(body: Json<HandleRequest>) -> Json<HandleResponse> {
-    log::info!("Creating new handle for user: {}", body.username);
-    let db = Database::new();
-    
-    if !db.check_login(&body.username, &body.password) {
-        log::warn!("Authentication failed during handle creation for user: {}", body.username);
-        return Json(HandleResponse {
-            success: false,
-            message: "Invalid username or password".to_string(),
-            handle: None,
-        });
-    }
-    
-    let user_result = db.get_user(&body.username);
-    if user_result.is_err() {
-        let err = user_result.err().unwrap();
-        log::error!("User not found during handle creation: {}, error: {:?}", body.username, err);
-        return Json(HandleResponse {
-            success: false,
-            message: "User not found".to_string(),
-            handle: None,
-        });
-    }
-    
-    let user = user_result.unwrap();
-    match Handle::new(&user, &db) {
+fn create_new_handle(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    client: ClientInfo,
+    body: Option<Json<HandleRequest>>,
+) -> Json<HandleResponse> {
+    let body = body.map(Json::into_inner).unwrap_or_default();
+    let auth = auth.and_then(|user| user.require_scope(Scope::HandlesWrite));
+    // A handle made with an API key can't do more than the key could
+    let scopes = auth.as_ref().ok().and_then(|user| user.scopes().map(<[Scope]>::to_vec));
+    // Nor outlive the handle it was made with
+    let expires_by = auth.as_ref().ok().and_then(AuthenticatedUser::handle).map(Handle::expires_at);
+    let (user, (authenticated_at, mfa_at)) = match authenticate(&db, auth, body.username.as_deref(), body.password.as_deref()) {
+        Ok(found) => found,
+        Err(message) => {
+            return Json(HandleResponse {
+                success: false,
+                message,
+                handle: None,
+                expires_at: None,
+            });
+        }
+    };
+
+    let client = match body.device_name.as_deref().map(check_device_name).transpose() {
+        Ok(name) => client.with_device_name(name),
+        Err(message) => {
+            return Json(HandleResponse {
+                success: false,
+                message,
+                handle: None,
+                expires_at: None,
+            });
+        }
+    };
+
+    // The new handle is only as freshly authenticated as whatever was used
+    // to create it
+    log::info!("Creating new handle for user: {}", user.username());
+    let handle = match &scopes {
+        Some(scopes) => Handle::new_scoped(&user, scopes, None, expires_by, &client, &db),
+        None => Handle::new(&user, authenticated_at, mfa_at, expires_by, &client, &db),
+    };
+    match handle {
         Ok(handle) => {
-            log::info!("Successfully created new handle {} for user: {}", handle.get(), body.username);
+            log::info!("Successfully created new handle #{} for user: {}", handle.id(), user.username());
             Json(HandleResponse {
                 success: true,
                 message: "Handle created successfully".to_string(),
-                handle: Some(handle.get()),
+                handle: handle.get().cloned(),
+                expires_at: Some(handle.expires_at()),
             })
         },
         Err(err) => {
-            log::error!("Failed to create handle for user {}: {:?}", body.username, err);
+            log::error!("Failed to create handle for user {}: {:?}", user.username(), err);
             Json(HandleResponse {
                 success: false,
                 message: format!("Failed to create handle: {:?}", err),
                 handle: None,
+                expires_at: None,
             })
         },
     }
 }
 
 #[post("/user/handle/delete", data = "<body>")]
-fn delete_handle(body: Json<DeleteHandleRequest>) -> Json<HandleResponse> {
-    log::info!("Deleting handle {} for user: {}", body.handle, body.username);
-    let db = Database::new();
-    
-    if !db.check_login(&body.username, &body.password) {
-        log::warn!("Authentication failed during handle deletion for user: {}", body.username);
-        return Json(HandleResponse {
-            success: false,
-            message: "Invalid username or password".to_string(),
-            handle: None,
-        });
-    }
-    
-    let user_result = db.get_user(&body.username);
-    if user_result.is_err() {
-        let err = user_result.err().unwrap();
-        log::error!("User not found during handle deletion: {}, error: {:?}", body.username, err);
-        return Json(HandleResponse {
-            success: false,
-            message: "User not found".to_string(),
-            handle: None,
-        });
-    }
-    
-    let user = user_result.unwrap();
-    let handle = Handle::from_value(body.handle);
-    
-    if !handle.is_owned_by_user(user.id(), &db) {
-        log::warn!("Attempt to delete handle {} that doesn't belong to user: {}", body.handle, body.username);
-        return Json(HandleResponse {
-            success: false,
-            message: "This handle does not belong to the user".to_string(),
-            handle: None,
-        });
-    }
+fn delete_handle(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    body: Json<DeleteHandleRequest>,
+) -> Json<HandleResponse> {
+    let auth = auth.map(RecentlyAuthenticatedUser::into_inner);
+    let user = match authenticate(&db, auth, body.username.as_deref(), body.password.as_deref()) {
+        Ok((user, _)) => user,
+        Err(message) => {
+            return Json(HandleResponse {
+                success: false,
+                message,
+                handle: None,
+                expires_at: None,
+            });
+        }
+    };
+
+    log::info!("Deleting handle for user: {}", user.username());
+    let handle = match Handle::find(&body.handle, &db) {
+        Ok(handle) if handle.is_owned_by_user(user.id(), &db) => handle,
+        _ => {
+            log::warn!("Attempt to delete a handle that doesn't belong to user: {}", user.username());
+            return Json(HandleResponse {
+                success: false,
+                message: "This handle does not belong to the user".to_string(),
+                handle: None,
+                expires_at: None,
+            });
+        }
+    };
     
     match handle.delete(user.id(), &db) {
         Ok(true) => {
-            log::info!("Successfully deleted handle {} for user: {}", body.handle, body.username);
+            log::info!("Successfully deleted handle #{} for user: {}", handle.id(), user.username());
             Json(HandleResponse {
                 success: true,
                 message: "Handle deleted successfully".to_string(),
-                handle: Some(handle.get()),
+                handle: Some(body.handle.clone()),
+                expires_at: None,
             })
         },
         Ok(false) => {
-            log::warn!("Handle {} not found during deletion for user: {}", body.handle, body.username);
+            log::warn!("Handle #{} not found during deletion for user: {}", handle.id(), user.username());
             Json(HandleResponse {
                 success: false,
                 message: "Handle not found".to_string(),
                 handle: None,
+                expires_at: None,
             })
         },
         Err(err) => {
-            log::error!("Error deleting handle {} for user {}: {:?}", body.handle, body.username, err);
+            log::error!("Error deleting handle #{} for user {}: {:?}", handle.id(), user.username(), err);
             Json(HandleResponse {
                 success: false,
                 message: format!("Error deleting handle: {}", err),
                 handle: None,
+                expires_at: None,
             })
         },
     }
 
 }
-use rocket::{serde::json::Json, Route};
-
-
-use rocket::get;
-use rocket::routes as rocket_routes;
-use crate::{database::Database, handle::Handle};
 
 pub fn get_routes() -> Vec<Route> {
     use rocket::routes;
     // rocket_routes![create_user, auth_user, get_user]
-    routes![create_user, auth_user, get_user, get_user_handles, create_new_handle, delete_handle]
+    routes![
+        create_user,
+        auth_user,
+        auth_user_mfa,
+        step_up,
+        change_password,
+        refresh_access_token,
+        get_user,
+        get_user_handles,
+        create_new_handle,
+        delete_handle,
+        get_factors,
+        enroll_factor,
+        confirm_factor,
+        rename_factor,
+        delete_factor,
+        get_recovery_codes,
+        regenerate_recovery_codes,
+        get_trusted_devices,
+        delete_trusted_device,
+        get_device_keys,
+        register_device_key,
+        delete_device_key,
+        get_api_keys,
+        create_api_key,
+        delete_api_key,
+        auth_device_nonce,
+        auth_device,
+        device_authorization,
+        oauth_token,
+        approve_device,
+        deny_device,
+        authorize_redirect,
+        authorize,
+        userinfo,
+        openid_configuration,
+        jwks,
+        introspect,
+        get_oauth_clients,
+        register_oauth_client,
+        delete_oauth_client,
+        logout,
+        logout_all,
+        get_sessions,
+        delete_session,
+        get_metrics
+    ]
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -204,8 +358,7 @@ pub struct UserCreateResponse {
 }
 
 #[post("/user/create", data = "<body>")]
-fn create_user(body: Json<UserCreateRequest>) -> Json<UserCreateResponse> {
-    let db = Database::new();
+fn create_user(db: Database, body: Json<UserCreateRequest>) -> Json<UserCreateResponse> {
     let result = db.add_user(&body.username, &body.password);
     let reply = if result.is_ok() {
         UserCreateResponse {
@@ -230,9 +383,8 @@ pub struct UserGetResponse {
 }
 
 #[get("/user/<username>")]
-fn get_user(username: String) -> Json<UserGetResponse> {
+fn get_user(db: Database, username: String) -> Json<UserGetResponse> {
     log::info!("Got {} user.", username);
-    let db = Database::new();
     let acc = db.get_user(&username);
     let reply = if acc.is_err() {
         UserGetResponse {
@@ -257,37 +409,2407 @@ fn get_user(username: String) -> Json<UserGetResponse> {
 pub struct UserAuthRequest {
     username: String,
     password: String,
+    // Lets a user with a second factor skip the code on a device they
+    // trusted at `/user/auth/mfa`
+    #[serde(default)]
+    trusted_device: Option<String>,
+    // Also hand out a signed access token, see `sign_access_token`
+    #[serde(default)]
+    access_token: bool,
+    // Shown when listing sessions, e.g. "Work laptop"
+    #[serde(default)]
+    device_name: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct UserAuthResponse {
     success: bool,
-    handle: Option<u64>,
+    handle: Option<HandleToken>,
+    // When the handle, or the MFA challenge, stops being valid
+    expires_at: Option<DateTime<Utc>>,
     message: String,
+    // Set instead of `handle` when the user has a second factor, to be
+    // answered at `/user/auth/mfa`
+    mfa_challenge: Option<String>,
+    // The factors that can answer the challenge
+    factors: Option<Vec<String>>,
+    // Set when the user asked to trust this device at `/user/auth/mfa`
+    trusted_device: Option<String>,
+    // Set when the client asked for one, along with when it expires
+    access_token: Option<String>,
+    access_token_expires_at: Option<DateTime<Utc>>,
+}
+
+impl UserAuthResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(UserAuthResponse {
+            success: false,
+            message: message.to_string(),
+            handle: None,
+            expires_at: None,
+            mfa_challenge: None,
+            factors: None,
+            trusted_device: None,
+            access_token: None,
+            access_token_expires_at: None,
+        })
+    }
+}
+
+// Finish a login by handing out a new handle, noting whether the user
+// entered a code as well as their password
+fn issue_handle(db: &Database, user: &Account, mfa: bool, client: &ClientInfo) -> Json<UserAuthResponse> {
+    let now = Utc::now();
+    issue_handle_at(db, user, now, mfa.then_some(now), client)
+}
+
+fn issue_handle_at(
+    db: &Database,
+    user: &Account,
+    authenticated_at: DateTime<Utc>,
+    mfa_at: Option<DateTime<Utc>>,
+    client: &ClientInfo,
+) -> Json<UserAuthResponse> {
+    match Handle::new(user, authenticated_at, mfa_at, None, client, db) {
+        Ok(handle) => {
+            log::info!("Issued handle #{} to user: {}", handle.id(), user.username());
+            Json(UserAuthResponse {
+                success: true,
+                message: "".to_string(),
+                handle: handle.get().cloned(),
+                expires_at: Some(handle.expires_at()),
+                mfa_challenge: None,
+                factors: None,
+                trusted_device: None,
+                access_token: None,
+                access_token_expires_at: None,
+            })
+        },
+        Err(err) => {
+            log::error!("Failed to create handle for user {}: {:?}", user.username(), err);
+            UserAuthResponse::failure(&format!("Failed to create handle: {}", err))
+        },
+    }
+}
+
+/// A short-lived JWT saying who the user is, signed like ID tokens, so
+/// services can check it against the JWKS without asking Abuelo. Returns it
+/// and when it expires.
+fn sign_access_token(db: &Database, user: &Account) -> rusqlite::Result<(String, DateTime<Utc>)> {
+    let key = db.current_signing_key()?;
+    let now = Utc::now();
+    let expires_at = now + db.session_config().access_token_ttl();
+    let claims = AccessTokenClaims {
+        iss: db.oidc_config().issuer.clone(),
+        sub: user.id().to_string(),
+        aud: db.session_config().access_token_audience.clone(),
+        client_id: jwt::ACCESS_TOKEN_CLIENT_ID.to_string(),
+        iat: now.timestamp(),
+        exp: expires_at.timestamp(),
+        jti: jwt::generate_token_id(),
+        user_id: user.id(),
+        username: user.username().to_string(),
+        premium: user.premium(),
+    };
+    Ok((key.sign(jwt::ACCESS_TOKEN_TYPE, &claims), expires_at))
+}
+
+// Add an access token to a successful login if the client asked for one.
+// The handle is still what the client refreshes it with, so the login
+// doesn't fail without one.
+fn with_access_token(
+    db: &Database,
+    user: &Account,
+    mut response: Json<UserAuthResponse>,
+    requested: bool,
+) -> Json<UserAuthResponse> {
+    if requested && response.success {
+        match sign_access_token(db, user) {
+            Ok((token, expires_at)) => {
+                response.access_token = Some(token);
+                response.access_token_expires_at = Some(expires_at);
+            },
+            Err(err) => log::error!("Failed to sign access token for user {}: {:?}", user.username(), err),
+        }
+    }
+    response
 }
 
 #[post("/user/auth", data = "<body>")]
-fn auth_user(body: Json<UserAuthRequest>) -> Json<UserAuthResponse> {
+fn auth_user(db: Database, client: ClientInfo, body: Json<UserAuthRequest>) -> Json<UserAuthResponse> {
     log::info!("Authing user rn.");
-    let db = Database::new();
-    let reply = if db.check_login(&body.username, &body.password) {
-        // TODO: get rid of unwraps here in favor of good responses
-        let inner_handle = db.get_user(&body.username).unwrap();
-        let handle = Handle::new(&inner_handle, &db).unwrap();
-        log::info!("All good");
-        let handle2 = Some(handle.get());
-        UserAuthResponse {
+    let client = match body.device_name.as_deref().map(check_device_name).transpose() {
+        Ok(name) => client.with_device_name(name),
+        Err(message) => return UserAuthResponse::failure(&message),
+    };
+    if !db.check_login(&body.username, &body.password) {
+        log::info!("Failed to auth user {}", body.username);
+        return UserAuthResponse::failure("Username or Password is invalid");
+    }
+
+    let user = match db.get_user(&body.username) {
+        Ok(user) => user,
+        Err(err) => {
+            log::error!("User not found after authenticating: {}, error: {:?}", body.username, err);
+            return UserAuthResponse::failure("User not found");
+        }
+    };
+
+    if !user.factors().any_enabled() {
+        return with_access_token(&db, &user, issue_handle(&db, &user, false, &client), body.access_token);
+    }
+    // A trusted device stands in for the code, but doesn't count as entering
+    // one for routes that need a recent authentication
+    if let Some(token) = &body.trusted_device {
+        match db.use_trusted_device(user.id(), token) {
+            Ok(true) => {
+                log::info!("Skipped MFA on a trusted device for user: {}", body.username);
+                return with_access_token(&db, &user, issue_handle(&db, &user, false, &client), body.access_token);
+            },
+            Ok(false) => log::info!("Ignoring invalid trusted device token for user: {}", body.username),
+            Err(err) => {
+                log::error!("Error checking trusted device for user {}: {:?}", body.username, err);
+            },
+        }
+    }
+    match db.add_mfa_challenge(user.id()) {
+        Ok((challenge, expires_at)) => {
+            log::info!("Sent MFA challenge to user: {}", body.username);
+            Json(UserAuthResponse {
+                success: false,
+                message: "A second factor is required".to_string(),
+                handle: None,
+                expires_at: Some(expires_at),
+                mfa_challenge: Some(challenge),
+                factors: Some(user.factors().available()),
+                trusted_device: None,
+                access_token: None,
+                access_token_expires_at: None,
+            })
+        },
+        Err(err) => {
+            log::error!("Failed to create MFA challenge for user {}: {:?}", body.username, err);
+            UserAuthResponse::failure(&format!("Failed to create MFA challenge: {}", err))
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UserAuthMfaRequest {
+    challenge: String,
+    code: String,
+    // Hand out a token that skips the code on this device from now on
+    #[serde(default)]
+    trust_device: bool,
+    // Shown when listing sessions and trusted devices, e.g. "Work laptop"
+    #[serde(default)]
+    device_name: Option<String>,
+    #[serde(default)]
+    access_token: bool,
+}
+
+#[post("/user/auth/mfa", data = "<body>")]
+fn auth_user_mfa(db: Database, client: ClientInfo, body: Json<UserAuthMfaRequest>) -> Json<UserAuthResponse> {
+    let user = match db.get_user_by_mfa_challenge(&body.challenge) {
+        Ok(user) => user,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return UserAuthResponse::failure("Invalid or expired MFA challenge");
+        }
+        Err(err) => {
+            log::error!("Error looking up MFA challenge: {:?}", err);
+            return UserAuthResponse::failure(&format!("Error looking up MFA challenge: {}", err));
+        }
+    };
+
+    if !check_second_factor(&db, &user, &body.code) {
+        log::warn!("Invalid MFA code for user: {}", user.username());
+        if let Err(err) = db.fail_mfa_challenge(&body.challenge) {
+            log::error!("Error recording failed MFA attempt for user {}: {:?}", user.username(), err);
+        }
+        return UserAuthResponse::failure("Invalid code");
+    }
+
+    let device_name = match body.device_name.as_deref().map(check_device_name).transpose() {
+        Ok(name) => name.filter(|name| !name.is_empty()),
+        Err(message) => return UserAuthResponse::failure(&message),
+    };
+
+    match db.delete_mfa_challenge(&body.challenge) {
+        Ok(true) => {
+            let client = client.with_device_name(device_name);
+            let response = issue_handle(&db, &user, true, &client);
+            let mut response = with_access_token(&db, &user, response, body.access_token);
+            if response.success && body.trust_device {
+                match db.add_trusted_device(user.id(), device_name) {
+                    Ok((device, token)) => {
+                        log::info!("Trusted device #{} for user: {}", device.id(), user.username());
+                        response.trusted_device = Some(token);
+                    },
+                    // The login itself still worked
+                    Err(err) => {
+                        log::error!("Failed to trust device for user {}: {:?}", user.username(), err);
+                    },
+                }
+            }
+            response
+        },
+        Ok(false) => UserAuthResponse::failure("Invalid or expired MFA challenge"),
+        Err(err) => {
+            log::error!("Error using up MFA challenge for user {}: {:?}", user.username(), err);
+            UserAuthResponse::failure(&format!("Error using up MFA challenge: {}", err))
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AccessTokenResponse {
+    success: bool,
+    message: String,
+    access_token: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// Swap the bearer handle for a fresh access token. Like any use of the
+// handle, this also keeps it from going idle.
+#[post("/user/token")]
+fn refresh_access_token(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<AccessTokenResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(AccessTokenResponse {
+                success: false,
+                message: err.to_string(),
+                access_token: None,
+                expires_at: None,
+            });
+        }
+    };
+
+    match sign_access_token(&db, &user) {
+        Ok((access_token, expires_at)) => Json(AccessTokenResponse {
             success: true,
             message: "".to_string(),
-            handle: handle2,
+            access_token: Some(access_token),
+            expires_at: Some(expires_at),
+        }),
+        Err(err) => {
+            log::error!("Failed to sign access token for user {}: {:?}", user.username(), err);
+            Json(AccessTokenResponse {
+                success: false,
+                message: format!("Failed to sign access token: {}", err),
+                access_token: None,
+                expires_at: None,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StepUpRequest {
+    password: String,
+    // Needed if the user has a second factor
+    #[serde(default)]
+    code: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StepUpResponse {
+    success: bool,
+    message: String,
+    // Sensitive routes accept the handle until this time
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[post("/user/auth/step-up", data = "<body>")]
+fn step_up(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<StepUpRequest>,
+) -> Json<StepUpResponse> {
+    let mut user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user,
+        Err(err) => {
+            return Json(StepUpResponse {
+                success: false,
+                message: err.to_string(),
+                expires_at: None,
+            });
         }
-    } else {
-        log::info!("Failed to auth user {}", body.username);
-        UserAuthResponse {
+    };
+
+    let username = user.account().username().to_string();
+    if !db.check_login(&username, &body.password) {
+        log::warn!("Step-up failed for user: {}", username);
+        return Json(StepUpResponse {
             success: false,
-            message: "Username or Password is invalid".to_string(),
-            handle: None,
+            message: "Invalid password".to_string(),
+            expires_at: None,
+        });
+    }
+    let mfa = user.account().factors().any_enabled();
+    if mfa {
+        let valid = match body.code.as_deref() {
+            Some(code) => check_second_factor(&db, user.account(), code),
+            None => false,
+        };
+        if !valid {
+            log::warn!("Invalid MFA code during step-up for user: {}", username);
+            return Json(StepUpResponse {
+                success: false,
+                message: "A valid code from a second factor is required".to_string(),
+                expires_at: None,
+            });
+        }
+    }
+
+    let handle = user.handle_mut().expect("API keys were turned away");
+    match handle.reauthenticate(mfa, &db) {
+        Ok(()) => {
+            log::info!("User {} stepped up handle #{}", username, handle.id());
+            Json(StepUpResponse {
+                success: true,
+                message: "".to_string(),
+                expires_at: Some(handle.authenticated_at() + db.session_config().step_up_ttl()),
+            })
+        },
+        Err(err) => {
+            log::error!("Error stepping up handle for user {}: {:?}", username, err);
+            Json(StepUpResponse {
+                success: false,
+                message: format!("Error stepping up handle: {}", err),
+                expires_at: None,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PasswordChangeRequest {
+    // Not needed if the handle was stepped up recently
+    #[serde(default)]
+    current_password: Option<String>,
+    new_password: String,
+    // Log out everywhere else, e.g. if the old password leaked
+    #[serde(default)]
+    revoke_other_handles: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PasswordChangeResponse {
+    success: bool,
+    message: String,
+    // Number of other handles that were revoked
+    revoked: usize,
+}
+
+impl PasswordChangeResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(PasswordChangeResponse {
+            success: false,
+            message: message.to_string(),
+            revoked: 0,
+        })
+    }
+}
+
+#[post("/user/password", data = "<body>")]
+fn change_password(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<PasswordChangeRequest>,
+) -> Json<PasswordChangeResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user,
+        Err(err) => return PasswordChangeResponse::failure(&err.to_string()),
+    };
+    let username = user.account().username().to_string();
+
+    // The current password stands in for the password half of a step-up.
+    // Users with a second factor still need to have entered a code recently.
+    let session = db.session_config();
+    let password_ok = match body.current_password.as_deref() {
+        Some(password) => {
+            if !db.check_login(&username, password) {
+                log::warn!("Wrong current password when changing password for user: {}", username);
+                return PasswordChangeResponse::failure("Invalid password");
+            }
+            true
+        },
+        None => user.password_is_recent(session),
+    };
+    if !password_ok || !user.mfa_is_recent(session) {
+        return PasswordChangeResponse::failure(&AuthError::StepUpRequired.to_string());
+    }
+    if body.new_password.is_empty() {
+        return PasswordChangeResponse::failure("The new password can't be empty");
+    }
+
+    if let Err(err) = db.set_password_hash(&username, &body.new_password) {
+        log::error!("Error changing password for user {}: {:?}", username, err);
+        return PasswordChangeResponse::failure(&format!("Error changing password: {}", err));
+    }
+    log::info!("User {} changed their password", username);
+
+    if !body.revoke_other_handles {
+        return Json(PasswordChangeResponse {
+            success: true,
+            message: "".to_string(),
+            revoked: 0,
+        });
+    }
+    let handle = user.handle().expect("API keys were turned away");
+    match db.delete_other_handles(user.account().id(), handle.id()) {
+        Ok(revoked) => {
+            log::info!("Revoked {} other handles of user {} after a password change", revoked, username);
+            Json(PasswordChangeResponse {
+                success: true,
+                message: "".to_string(),
+                revoked,
+            })
+        },
+        // The password was still changed
+        Err(err) => {
+            log::error!("Error revoking other handles of user {}: {:?}", username, err);
+            Json(PasswordChangeResponse {
+                success: true,
+                message: format!("Password changed, but other handles couldn't be revoked: {}", err),
+                revoked: 0,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorsResponse {
+    success: bool,
+    message: String,
+    factors: Option<Vec<Factor>>,
+}
+
+#[get("/user/mfa/factors")]
+fn get_factors(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<FactorsResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(FactorsResponse {
+                success: false,
+                message: err.to_string(),
+                factors: None,
+            });
         }
     };
-    Json(reply)
-}
\ No newline at end of file
+
+    match db.get_all_factors(user.id()) {
+        Ok(factors) => Json(FactorsResponse {
+            success: true,
+            message: "".to_string(),
+            factors: Some(factors),
+        }),
+        Err(err) => {
+            log::error!("Error retrieving factors for user {}: {:?}", user.username(), err);
+            Json(FactorsResponse {
+                success: false,
+                message: format!("Error retrieving factors: {}", err),
+                factors: None,
+            })
+        },
+    }
+}
+
+const MAX_FACTOR_NAME_LEN: usize = 64;
+
+fn check_factor_name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_FACTOR_NAME_LEN {
+        return Err(format!("Factor names must be 1 to {} characters", MAX_FACTOR_NAME_LEN));
+    }
+    Ok(name)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorEnrollRequest {
+    name: String,
+    #[serde(rename = "type")]
+    kind: FactorKind,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorEnrollResponse {
+    success: bool,
+    message: String,
+    // To confirm the factor with
+    id: Option<FactorID>,
+    // Base32, for typing into an authenticator app by hand
+    secret: Option<String>,
+    // `otpauth://` URI, usually shown as a QR code
+    uri: Option<String>,
+}
+
+#[post("/user/mfa/factors/enroll", data = "<body>")]
+fn enroll_factor(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    body: Json<FactorEnrollRequest>,
+) -> Json<FactorEnrollResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(FactorEnrollResponse {
+                success: false,
+                message: err.to_string(),
+                id: None,
+                secret: None,
+                uri: None,
+            });
+        }
+    };
+    let name = match check_factor_name(&body.name) {
+        Ok(name) => name,
+        Err(message) => {
+            return Json(FactorEnrollResponse {
+                success: false,
+                message,
+                id: None,
+                secret: None,
+                uri: None,
+            });
+        }
+    };
+
+    let secret = mfa::generate_otp_secret();
+    match db.add_pending_factor(user.id(), name, body.kind, &secret) {
+        Ok(factor) => {
+            log::info!("Started enrolling factor #{} for user: {}", factor.id(), user.username());
+            let (secret, uri) = mfa::enrollment(body.kind, &secret, user.username(), db.mfa_config());
+            Json(FactorEnrollResponse {
+                success: true,
+                message: "".to_string(),
+                id: Some(factor.id()),
+                secret: Some(secret),
+                uri: Some(uri),
+            })
+        },
+        Err(err) => {
+            if let FactorDBError::DBError(e) = &err {
+                log::error!("Error enrolling factor for user {}: {:?}", user.username(), e);
+            }
+            Json(FactorEnrollResponse {
+                success: false,
+                message: err.to_string(),
+                id: None,
+                secret: None,
+                uri: None,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorConfirmRequest {
+    code: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorConfirmResponse {
+    success: bool,
+    message: String,
+    // Only when this is the user's first factor. Shown to the user once;
+    // only hashes are kept.
+    recovery_codes: Option<Vec<String>>,
+}
+
+#[post("/user/mfa/factors/<id>/confirm", data = "<body>")]
+fn confirm_factor(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    id: FactorID,
+    body: Json<FactorConfirmRequest>,
+) -> Json<FactorConfirmResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(FactorConfirmResponse {
+                success: false,
+                message: err.to_string(),
+                recovery_codes: None,
+            });
+        }
+    };
+
+    let factor = match db.get_factor(user.id(), id) {
+        Ok(factor) if !factor.is_confirmed() => factor,
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Json(FactorConfirmResponse {
+                success: false,
+                message: "No factor enrollment to confirm".to_string(),
+                recovery_codes: None,
+            });
+        }
+        Err(err) => {
+            log::error!("Error looking up factor #{} for user {}: {:?}", id, user.username(), err);
+            return Json(FactorConfirmResponse {
+                success: false,
+                message: format!("Error looking up factor: {}", err),
+                recovery_codes: None,
+            });
+        }
+    };
+
+    let Some(counter) = factor.matching_counter(&body.code, db.mfa_config()) else {
+        log::warn!("Invalid code while confirming factor #{} for user: {}", id, user.username());
+        return Json(FactorConfirmResponse {
+            success: false,
+            message: "Invalid code".to_string(),
+            recovery_codes: None,
+        });
+    };
+
+    let recovery_codes = if user.factors().any_enabled() {
+        None
+    } else {
+        Some(mfa::generate_recovery_codes(db.mfa_config().recovery_codes))
+    };
+    match db.confirm_factor(user.id(), id, counter, recovery_codes.as_deref()) {
+        Ok(false) => Json(FactorConfirmResponse {
+            success: false,
+            message: "No factor enrollment to confirm".to_string(),
+            recovery_codes: None,
+        }),
+        Ok(true) => {
+            log::info!("Enabled factor #{} for user: {}", id, user.username());
+            Json(FactorConfirmResponse {
+                success: true,
+                message: "".to_string(),
+                recovery_codes,
+            })
+        },
+        Err(err) => {
+            log::error!("Error enabling factor #{} for user {}: {:?}", id, user.username(), err);
+            Json(FactorConfirmResponse {
+                success: false,
+                message: format!("Error enabling factor: {}", err),
+                recovery_codes: None,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorRenameRequest {
+    name: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FactorResponse {
+    success: bool,
+    message: String,
+}
+
+#[post("/user/mfa/factors/<id>/rename", data = "<body>")]
+fn rename_factor(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    id: FactorID,
+    body: Json<FactorRenameRequest>,
+) -> Json<FactorResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(FactorResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+    let name = match check_factor_name(&body.name) {
+        Ok(name) => name,
+        Err(message) => {
+            return Json(FactorResponse {
+                success: false,
+                message,
+            });
+        }
+    };
+
+    match db.rename_factor(user.id(), id, name) {
+        Ok(true) => {
+            log::info!("Renamed factor #{} for user: {}", id, user.username());
+            Json(FactorResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(FactorResponse {
+            success: false,
+            message: "Factor not found".to_string(),
+        }),
+        Err(err) => {
+            if let FactorDBError::DBError(e) = &err {
+                log::error!("Error renaming factor #{} for user {}: {:?}", id, user.username(), e);
+            }
+            Json(FactorResponse {
+                success: false,
+                message: err.to_string(),
+            })
+        },
+    }
+}
+
+#[post("/user/mfa/factors/<id>/delete")]
+fn delete_factor(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    id: FactorID,
+) -> Json<FactorResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(FactorResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.delete_factor(user.id(), id) {
+        Ok(true) => {
+            log::info!("Deleted factor #{} for user: {}", id, user.username());
+            Json(FactorResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(FactorResponse {
+            success: false,
+            message: "Factor not found".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error deleting factor #{} for user {}: {:?}", id, user.username(), err);
+            Json(FactorResponse {
+                success: false,
+                message: format!("Error deleting factor: {}", err),
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RecoveryCodesResponse {
+    success: bool,
+    message: String,
+    // Number of unused recovery codes
+    remaining: Option<usize>,
+    // Only set when new codes were just generated
+    codes: Option<Vec<String>>,
+}
+
+#[get("/user/mfa/recovery-codes")]
+fn get_recovery_codes(auth: Result<AuthenticatedUser, AuthError>) -> Json<RecoveryCodesResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(RecoveryCodesResponse {
+                success: false,
+                message: err.to_string(),
+                remaining: None,
+                codes: None,
+            });
+        }
+    };
+
+    Json(RecoveryCodesResponse {
+        success: true,
+        message: "".to_string(),
+        remaining: Some(user.factors().recovery_codes()),
+        codes: None,
+    })
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RegenerateRecoveryCodesRequest {
+    // A current code from one of the user's factors; a bearer handle alone
+    // isn't enough
+    code: String,
+}
+
+#[post("/user/mfa/recovery-codes/regenerate", data = "<body>")]
+fn regenerate_recovery_codes(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<RegenerateRecoveryCodesRequest>,
+) -> Json<RecoveryCodesResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(RecoveryCodesResponse {
+                success: false,
+                message: err.to_string(),
+                remaining: None,
+                codes: None,
+            });
+        }
+    };
+
+    if !user.factors().any_enabled() {
+        return Json(RecoveryCodesResponse {
+            success: false,
+            message: "No second factor is enabled".to_string(),
+            remaining: None,
+            codes: None,
+        });
+    }
+    if !db.check_factors(&user, &body.code) {
+        log::warn!("Invalid code while regenerating recovery codes for user: {}", user.username());
+        return Json(RecoveryCodesResponse {
+            success: false,
+            message: "Invalid code".to_string(),
+            remaining: None,
+            codes: None,
+        });
+    }
+
+    let codes = mfa::generate_recovery_codes(db.mfa_config().recovery_codes);
+    match db.set_recovery_codes(user.id(), &codes) {
+        Ok(()) => {
+            log::info!("Regenerated recovery codes for user: {}", user.username());
+            Json(RecoveryCodesResponse {
+                success: true,
+                message: "".to_string(),
+                remaining: Some(codes.len()),
+                codes: Some(codes),
+            })
+        },
+        Err(err) => {
+            log::error!("Error regenerating recovery codes for user {}: {:?}", user.username(), err);
+            Json(RecoveryCodesResponse {
+                success: false,
+                message: format!("Error regenerating recovery codes: {}", err),
+                remaining: None,
+                codes: None,
+            })
+        },
+    }
+}
+
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+fn check_device_name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.chars().count() > MAX_DEVICE_NAME_LEN {
+        return Err(format!("Device names must be at most {} characters", MAX_DEVICE_NAME_LEN));
+    }
+    Ok(name)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TrustedDevicesResponse {
+    success: bool,
+    message: String,
+    devices: Option<Vec<TrustedDevice>>,
+}
+
+#[get("/user/mfa/trusted-devices")]
+fn get_trusted_devices(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+) -> Json<TrustedDevicesResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(TrustedDevicesResponse {
+                success: false,
+                message: err.to_string(),
+                devices: None,
+            });
+        }
+    };
+
+    match db.get_trusted_devices(user.id()) {
+        Ok(devices) => Json(TrustedDevicesResponse {
+            success: true,
+            message: "".to_string(),
+            devices: Some(devices),
+        }),
+        Err(err) => {
+            log::error!("Error retrieving trusted devices for user {}: {:?}", user.username(), err);
+            Json(TrustedDevicesResponse {
+                success: false,
+                message: format!("Error retrieving trusted devices: {}", err),
+                devices: None,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TrustedDeviceDeleteResponse {
+    success: bool,
+    message: String,
+}
+
+#[post("/user/mfa/trusted-devices/<id>/delete")]
+fn delete_trusted_device(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    id: TrustedDeviceID,
+) -> Json<TrustedDeviceDeleteResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(TrustedDeviceDeleteResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.delete_trusted_device(user.id(), id) {
+        Ok(true) => {
+            log::info!("Revoked trusted device #{} for user: {}", id, user.username());
+            Json(TrustedDeviceDeleteResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(TrustedDeviceDeleteResponse {
+            success: false,
+            message: "Trusted device not found".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error revoking trusted device #{} for user {}: {:?}", id, user.username(), err);
+            Json(TrustedDeviceDeleteResponse {
+                success: false,
+                message: format!("Error revoking trusted device: {}", err),
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceKeysResponse {
+    success: bool,
+    message: String,
+    keys: Option<Vec<DeviceKey>>,
+}
+
+#[get("/user/device-keys")]
+fn get_device_keys(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<DeviceKeysResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(DeviceKeysResponse {
+                success: false,
+                message: err.to_string(),
+                keys: None,
+            });
+        }
+    };
+
+    match db.get_device_keys(user.id()) {
+        Ok(keys) => Json(DeviceKeysResponse {
+            success: true,
+            message: "".to_string(),
+            keys: Some(keys),
+        }),
+        Err(err) => {
+            log::error!("Error retrieving device keys for user {}: {:?}", user.username(), err);
+            Json(DeviceKeysResponse {
+                success: false,
+                message: format!("Error retrieving device keys: {}", err),
+                keys: None,
+            })
+        },
+    }
+}
+
+const MAX_DEVICE_KEY_NAME_LEN: usize = 64;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceKeyRegisterRequest {
+    name: String,
+    // Raw 32 byte Ed25519 public key in standard base64
+    public_key: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceKeyRegisterResponse {
+    success: bool,
+    message: String,
+    id: Option<DeviceKeyID>,
+}
+
+impl DeviceKeyRegisterResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(DeviceKeyRegisterResponse {
+            success: false,
+            message: message.to_string(),
+            id: None,
+        })
+    }
+}
+
+// Lets a device log in as the user without their password, so it needs the
+// same recent authentication as other sensitive changes
+#[post("/user/device-keys/register", data = "<body>")]
+fn register_device_key(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    body: Json<DeviceKeyRegisterRequest>,
+) -> Json<DeviceKeyRegisterResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => return DeviceKeyRegisterResponse::failure(&err.to_string()),
+    };
+
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_DEVICE_KEY_NAME_LEN {
+        return DeviceKeyRegisterResponse::failure(&format!(
+            "Device key names must be 1 to {} characters",
+            MAX_DEVICE_KEY_NAME_LEN
+        ));
+    }
+    let Some(public_key) = device_key::parse_public_key(&body.public_key) else {
+        return DeviceKeyRegisterResponse::failure("Public keys must be 32 byte Ed25519 keys in base64");
+    };
+
+    match db.add_device_key(user.id(), name, &public_key) {
+        Ok(key) => {
+            log::info!("Registered device key #{} ({}) for user: {}", key.id(), key.name(), user.username());
+            Json(DeviceKeyRegisterResponse {
+                success: true,
+                message: "".to_string(),
+                id: Some(key.id()),
+            })
+        },
+        Err(DeviceKeyDBError::DBError(err)) => {
+            log::error!("Error registering device key for user {}: {:?}", user.username(), err);
+            DeviceKeyRegisterResponse::failure(&format!("Error registering device key: {}", err))
+        },
+        Err(err) => DeviceKeyRegisterResponse::failure(&err.to_string()),
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceKeyDeleteResponse {
+    success: bool,
+    message: String,
+}
+
+#[post("/user/device-keys/<id>/delete")]
+fn delete_device_key(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    id: DeviceKeyID,
+) -> Json<DeviceKeyDeleteResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(DeviceKeyDeleteResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.delete_device_key(user.id(), id) {
+        Ok(true) => {
+            log::info!("Deleted device key #{} for user: {}", id, user.username());
+            Json(DeviceKeyDeleteResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(DeviceKeyDeleteResponse {
+            success: false,
+            message: "Device key not found".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error deleting device key #{} for user {}: {:?}", id, user.username(), err);
+            Json(DeviceKeyDeleteResponse {
+                success: false,
+                message: format!("Error deleting device key: {}", err),
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ApiKeysResponse {
+    success: bool,
+    message: String,
+    keys: Option<Vec<ApiKey>>,
+}
+
+#[get("/user/api-keys")]
+fn get_api_keys(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<ApiKeysResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(ApiKeysResponse {
+                success: false,
+                message: err.to_string(),
+                keys: None,
+            });
+        }
+    };
+
+    match db.get_api_keys(user.id()) {
+        Ok(keys) => Json(ApiKeysResponse {
+            success: true,
+            message: "".to_string(),
+            keys: Some(keys),
+        }),
+        Err(err) => {
+            log::error!("Error retrieving API keys for user {}: {:?}", user.username(), err);
+            Json(ApiKeysResponse {
+                success: false,
+                message: format!("Error retrieving API keys: {}", err),
+                keys: None,
+            })
+        },
+    }
+}
+
+const MAX_API_KEY_DESCRIPTION_LEN: usize = 128;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ApiKeyCreateRequest {
+    description: String,
+    scopes: Vec<String>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ApiKeyCreateResponse {
+    success: bool,
+    message: String,
+    id: Option<ApiKeyID>,
+    // Only ever shown here
+    key: Option<String>,
+}
+
+impl ApiKeyCreateResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(ApiKeyCreateResponse {
+            success: false,
+            message: message.to_string(),
+            id: None,
+            key: None,
+        })
+    }
+}
+
+// API keys don't expire unless asked to, so making one needs the same
+// recent authentication as other sensitive changes
+#[post("/user/api-keys/create", data = "<body>")]
+fn create_api_key(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    body: Json<ApiKeyCreateRequest>,
+) -> Json<ApiKeyCreateResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => return ApiKeyCreateResponse::failure(&err.to_string()),
+    };
+
+    let description = body.description.trim();
+    if description.is_empty() || description.chars().count() > MAX_API_KEY_DESCRIPTION_LEN {
+        return ApiKeyCreateResponse::failure(&format!(
+            "API key descriptions must be 1 to {} characters",
+            MAX_API_KEY_DESCRIPTION_LEN
+        ));
+    }
+    let mut scopes = Vec::new();
+    for scope in &body.scopes {
+        match Scope::parse(scope) {
+            Some(scope) if !scopes.contains(&scope) => scopes.push(scope),
+            Some(_) => {},
+            None => return ApiKeyCreateResponse::failure(&format!("Unknown scope: {}", scope)),
+        }
+    }
+    if scopes.is_empty() {
+        return ApiKeyCreateResponse::failure("API keys need at least one scope");
+    }
+    if body.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return ApiKeyCreateResponse::failure("API keys can't expire in the past");
+    }
+
+    match db.add_api_key(user.id(), description, &scopes, body.expires_at) {
+        Ok((key, token)) => {
+            log::info!("Created API key #{} ({}) for user: {}", key.id(), key.description(), user.username());
+            Json(ApiKeyCreateResponse {
+                success: true,
+                message: "".to_string(),
+                id: Some(key.id()),
+                key: Some(token),
+            })
+        },
+        Err(err) => {
+            log::error!("Error creating API key for user {}: {:?}", user.username(), err);
+            ApiKeyCreateResponse::failure(&format!("Error creating API key: {}", err))
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ApiKeyDeleteResponse {
+    success: bool,
+    message: String,
+}
+
+#[post("/user/api-keys/<id>/delete")]
+fn delete_api_key(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    id: ApiKeyID,
+) -> Json<ApiKeyDeleteResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(ApiKeyDeleteResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.delete_api_key(user.id(), id) {
+        Ok(true) => {
+            log::info!("Deleted API key #{} for user: {}", id, user.username());
+            Json(ApiKeyDeleteResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(ApiKeyDeleteResponse {
+            success: false,
+            message: "API key not found".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error deleting API key #{} for user {}: {:?}", id, user.username(), err);
+            Json(ApiKeyDeleteResponse {
+                success: false,
+                message: format!("Error deleting API key: {}", err),
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceNonceRequest {
+    username: String,
+    key_id: DeviceKeyID,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceNonceResponse {
+    success: bool,
+    message: String,
+    nonce: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl DeviceNonceResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(DeviceNonceResponse {
+            success: false,
+            message: message.to_string(),
+            nonce: None,
+            expires_at: None,
+        })
+    }
+}
+
+#[post("/user/auth/device/nonce", data = "<body>")]
+fn auth_device_nonce(db: Database, body: Json<DeviceNonceRequest>) -> Json<DeviceNonceResponse> {
+    // Same message either way, so this doesn't reveal which keys exist
+    let not_found = "Unknown user or device key";
+    let user = match db.get_user(&body.username) {
+        Ok(user) => user,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return DeviceNonceResponse::failure(not_found),
+        Err(err) => {
+            log::error!("Error looking up user {}: {:?}", body.username, err);
+            return DeviceNonceResponse::failure(&format!("Error looking up user: {}", err));
+        }
+    };
+
+    match db.add_device_nonce(user.id(), body.key_id) {
+        Ok((nonce, expires_at)) => Json(DeviceNonceResponse {
+            success: true,
+            message: "".to_string(),
+            nonce: Some(nonce),
+            expires_at: Some(expires_at),
+        }),
+        Err(rusqlite::Error::QueryReturnedNoRows) => DeviceNonceResponse::failure(not_found),
+        Err(err) => {
+            log::error!("Error creating device nonce for user {}: {:?}", body.username, err);
+            DeviceNonceResponse::failure(&format!("Error creating nonce: {}", err))
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceAuthRequest {
+    nonce: String,
+    // Ed25519 signature over the nonce, in standard base64
+    signature: String,
+}
+
+#[post("/user/auth/device", data = "<body>")]
+fn auth_device(db: Database, client: ClientInfo, body: Json<DeviceAuthRequest>) -> Json<UserAuthResponse> {
+    let (key_id, public_key) = match db.use_device_nonce(&body.nonce) {
+        Ok(found) => found,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return UserAuthResponse::failure("Invalid or expired nonce");
+        }
+        Err(err) => {
+            log::error!("Error looking up device nonce: {:?}", err);
+            return UserAuthResponse::failure(&format!("Error looking up nonce: {}", err));
+        }
+    };
+
+    if !device_key::verify(&public_key, &body.nonce, &body.signature) {
+        log::warn!("Invalid signature for device key #{}", key_id);
+        return UserAuthResponse::failure("Invalid signature");
+    }
+
+    let (user, key_name) = match db.use_device_key(key_id) {
+        Ok(found) => found,
+        Err(err) => {
+            log::error!("Error looking up owner of device key #{}: {:?}", key_id, err);
+            return UserAuthResponse::failure(&format!("Error looking up device key: {}", err));
+        }
+    };
+    log::info!("User {} logged in with device key #{}", user.username(), key_id);
+    // The key stands in for the password, but the user hasn't entered it,
+    // so routes needing a recent authentication still need a step-up
+    let client = client.with_device_name(Some(&key_name));
+    issue_handle_at(&db, &user, DateTime::UNIX_EPOCH, None, &client)
+}
+
+/// Form-encoded, as OAuth requires
+#[derive(FromForm)]
+pub struct DeviceAuthorizationRequest {
+    // Names the tool asking, e.g. "able-cli"
+    client_id: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: i64,
+}
+
+// Start of the device authorization grant (RFC 8628 section 3.1). Responses
+// follow the RFC rather than the rest of the API, so existing OAuth
+// libraries can be used.
+#[post("/oauth/device_authorization", data = "<body>")]
+fn device_authorization(
+    db: Database,
+    body: Form<DeviceAuthorizationRequest>,
+) -> OAuthResult<DeviceAuthorizationResponse> {
+    let client_id = match body.client_id.as_deref().map(str::trim) {
+        Some(client_id) if !client_id.is_empty() => client_id,
+        _ => {
+            return Err((
+                Status::BadRequest,
+                Json(OAuthError::with_description("invalid_request", "client_id is required")),
+            ));
+        }
+    };
+    if !db.device_grant_config().allows_client(client_id) {
+        log::warn!("Device authorization attempted by unknown client: {}", client_id);
+        return oauth_error(Status::Unauthorized, "invalid_client");
+    }
+
+    match db.add_device_grant(client_id) {
+        Ok((device_code, user_code)) => {
+            let config = db.device_grant_config();
+            let user_code = oauth::format_user_code(&user_code);
+            log::info!("Started device authorization for client: {}", client_id);
+            Ok(Json(DeviceAuthorizationResponse {
+                device_code,
+                verification_uri_complete: format!("{}?user_code={}", config.verification_uri, user_code),
+                user_code,
+                verification_uri: config.verification_uri.clone(),
+                expires_in: config.code_ttl,
+                interval: config.interval,
+            }))
+        },
+        Err(err) => {
+            log::error!("Failed to start device authorization for client {}: {:?}", client_id, err);
+            oauth_error(Status::InternalServerError, "server_error")
+        },
+    }
+}
+
+/// Error responses of OAuth endpoints, see `OAuthError`
+type OAuthResult<T> = Result<Json<T>, (Status, Json<OAuthError>)>;
+
+fn oauth_error<T>(status: Status, error: &str) -> OAuthResult<T> {
+    Err((status, Json(OAuthError::new(error))))
+}
+
+#[derive(FromForm)]
+pub struct TokenRequest {
+    grant_type: Option<String>,
+    client_id: Option<String>,
+    // For the device authorization grant
+    device_code: Option<String>,
+    // For the authorization code grant
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TokenResponse {
+    // A handle. For the authorization code grant it's limited to the
+    // granted scopes, so it can't be used on the account routes.
+    access_token: HandleToken,
+    token_type: String,
+    // Seconds until the handle reaches its absolute expiry
+    expires_in: i64,
+    // Only for the authorization code grant with the `openid` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+// Redeems a grant for a handle: a device code once the user approved it
+// (RFC 8628 section 3.4), or an authorization code (RFC 6749 section 4.1.3)
+#[post("/oauth/token", data = "<body>")]
+fn oauth_token(db: Database, client: ClientInfo, body: Form<TokenRequest>) -> OAuthResult<TokenResponse> {
+    match body.grant_type.as_deref() {
+        Some(oauth::DEVICE_CODE_GRANT) => device_code_token(&db, &body, client),
+        Some(oauth::AUTHORIZATION_CODE_GRANT) => authorization_code_token(&db, &body, client),
+        _ => oauth_error(Status::BadRequest, "unsupported_grant_type"),
+    }
+}
+
+fn device_code_token(db: &Database, body: &TokenRequest, client_info: ClientInfo) -> OAuthResult<TokenResponse> {
+    let (Some(device_code), Some(client_id)) = (&body.device_code, &body.client_id) else {
+        return oauth_error(Status::BadRequest, "invalid_request");
+    };
+
+    let user = match db.poll_device_grant(device_code, client_id) {
+        Ok(DeviceGrantPoll::Approved(user)) => user,
+        Ok(DeviceGrantPoll::Pending) => return oauth_error(Status::BadRequest, "authorization_pending"),
+        Ok(DeviceGrantPoll::SlowDown) => return oauth_error(Status::BadRequest, "slow_down"),
+        Ok(DeviceGrantPoll::Denied) => return oauth_error(Status::BadRequest, "access_denied"),
+        Ok(DeviceGrantPoll::Expired) => return oauth_error(Status::BadRequest, "expired_token"),
+        Ok(DeviceGrantPoll::Invalid) => return oauth_error(Status::BadRequest, "invalid_grant"),
+        Err(err) => {
+            log::error!("Error polling device authorization for client {}: {:?}", client_id, err);
+            return oauth_error(Status::InternalServerError, "server_error");
+        }
+    };
+    // Device grant clients aren't registered, so their ID is all there is
+    let client_info = client_info.with_device_name(Some(client_id));
+    issue_token(db, &user, client_id, None, None, &client_info)
+}
+
+fn authorization_code_token(db: &Database, body: &TokenRequest, client_info: ClientInfo) -> OAuthResult<TokenResponse> {
+    let (Some(code), Some(client_id), Some(redirect_uri), Some(code_verifier)) =
+        (&body.code, &body.client_id, &body.redirect_uri, &body.code_verifier)
+    else {
+        return oauth_error(Status::BadRequest, "invalid_request");
+    };
+
+    let client = match db.get_oauth_client(client_id) {
+        Ok(client) => client,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return oauth_error(Status::Unauthorized, "invalid_client"),
+        Err(err) => {
+            log::error!("Error looking up OAuth client {}: {:?}", client_id, err);
+            return oauth_error(Status::InternalServerError, "server_error");
+        }
+    };
+    if client.is_confidential() {
+        let authenticated = match &body.client_secret {
+            Some(client_secret) => db.check_client_secret(client_id, client_secret).unwrap_or_else(|err| {
+                log::error!("Error checking secret of OAuth client {}: {:?}", client_id, err);
+                false
+            }),
+            None => false,
+        };
+        if !authenticated {
+            log::warn!("Invalid secret for OAuth client: {}", client_id);
+            return oauth_error(Status::Unauthorized, "invalid_client");
+        }
+    }
+
+    let grant = match db.use_authorization_code(code) {
+        Ok(Some(grant)) => grant,
+        Ok(None) => return oauth_error(Status::BadRequest, "invalid_grant"),
+        Err(err) => {
+            log::error!("Error looking up authorization code for client {}: {:?}", client_id, err);
+            return oauth_error(Status::InternalServerError, "server_error");
+        }
+    };
+    // The code is used up either way, so it can't be guessed at
+    if grant.client_id != *client_id
+        || grant.redirect_uri != *redirect_uri
+        || !oauth::verify_pkce(code_verifier, &grant.code_challenge)
+    {
+        log::warn!("Authorization code redeemed with the wrong client, redirect URI or verifier: {}", client_id);
+        return oauth_error(Status::BadRequest, "invalid_grant");
+    }
+
+    let user = match db.get_user_by_id(grant.user_id) {
+        Ok(user) => user,
+        Err(err) => {
+            log::error!("User #{} not found for authorization code: {:?}", grant.user_id, err);
+            return oauth_error(Status::BadRequest, "invalid_grant");
+        }
+    };
+
+    let id_token = if grant.scope.split(' ').any(|scope| scope == "openid") {
+        let key = match db.current_signing_key() {
+            Ok(key) => key,
+            Err(err) => {
+                log::error!("Failed to get a signing key: {:?}", err);
+                return oauth_error(Status::InternalServerError, "server_error");
+            }
+        };
+        let config = db.oidc_config();
+        let now = Utc::now();
+        Some(key.sign(jwt::ID_TOKEN_TYPE, &IdTokenClaims {
+            iss: config.issuer.clone(),
+            sub: user.id().to_string(),
+            aud: client_id.clone(),
+            exp: (now + config.id_token_ttl()).timestamp(),
+            iat: now.timestamp(),
+            auth_time: grant.auth_time.map(|auth_time| auth_time.timestamp()),
+            nonce: grant.nonce,
+            preferred_username: user.username().to_string(),
+            premium: user.premium(),
+        }))
+    } else {
+        None
+    };
+    let client_info = client_info.with_device_name(Some(client.name()));
+    let scopes = oauth::token_scopes(&grant.scope);
+    issue_token(db, &user, client_id, id_token, Some(&scopes), &client_info)
+}
+
+// Hands a client a handle for the user, limited to `scopes` if set. Nobody
+// entered a password for it, so like device key logins it never counts as a
+// recent authentication.
+fn issue_token(
+    db: &Database,
+    user: &Account,
+    client_id: &str,
+    id_token: Option<String>,
+    scopes: Option<&[Scope]>,
+    client_info: &ClientInfo,
+) -> OAuthResult<TokenResponse> {
+    let handle = match scopes {
+        Some(scopes) => Handle::new_scoped(user, scopes, Some(client_id), None, client_info, db),
+        None => Handle::new(user, DateTime::UNIX_EPOCH, None, None, client_info, db),
+    };
+    match handle {
+        Ok(handle) => {
+            log::info!("Issued handle #{} to client {} for user: {}", handle.id(), client_id, user.username());
+            Ok(Json(TokenResponse {
+                access_token: handle.get().cloned().expect("new handles know their token"),
+                token_type: "Bearer".to_string(),
+                expires_in: (handle.expires_at() - Utc::now()).num_seconds(),
+                id_token,
+            }))
+        },
+        Err(err) => {
+            log::error!("Failed to create handle for user {}: {:?}", user.username(), err);
+            oauth_error(Status::InternalServerError, "server_error")
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceApprovalRequest {
+    user_code: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeviceApprovalResponse {
+    success: bool,
+    message: String,
+}
+
+// Let a device that showed the user this code have a handle for their account
+#[post("/user/device/approve", data = "<body>")]
+fn approve_device(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<DeviceApprovalRequest>,
+) -> Json<DeviceApprovalResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(DeviceApprovalResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.approve_device_grant(&oauth::normalize_user_code(&body.user_code), user.id()) {
+        Ok(true) => {
+            log::info!("User {} approved a device", user.username());
+            Json(DeviceApprovalResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(DeviceApprovalResponse {
+            success: false,
+            message: "Invalid or expired code".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error approving device for user {}: {:?}", user.username(), err);
+            Json(DeviceApprovalResponse {
+                success: false,
+                message: format!("Error approving device: {}", err),
+            })
+        },
+    }
+}
+
+#[post("/user/device/deny", data = "<body>")]
+fn deny_device(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<DeviceApprovalRequest>,
+) -> Json<DeviceApprovalResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(DeviceApprovalResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.deny_device_grant(&oauth::normalize_user_code(&body.user_code)) {
+        Ok(true) => {
+            log::info!("User {} denied a device", user.username());
+            Json(DeviceApprovalResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(DeviceApprovalResponse {
+            success: false,
+            message: "Invalid or expired code".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error denying device for user {}: {:?}", user.username(), err);
+            Json(DeviceApprovalResponse {
+                success: false,
+                message: format!("Error denying device: {}", err),
+            })
+        },
+    }
+}
+
+// Where relying parties send users to log in (RFC 6749 section 4.1.1). The
+// web frontend does the logging in and asking, so pass the request on to it
+// as is.
+#[get("/oauth/authorize")]
+fn authorize_redirect(db: Database, uri: &Origin<'_>) -> Redirect {
+    let login_page = &db.oidc_config().login_page;
+    match uri.query() {
+        Some(query) => Redirect::to(format!("{}?{}", login_page, query)),
+        None => Redirect::to(login_page.clone()),
+    }
+}
+
+/// The parameters `/oauth/authorize` was sent, plus whether the user
+/// approved the client
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AuthorizeRequest {
+    client_id: String,
+    redirect_uri: String,
+    response_type: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    code_challenge: Option<String>,
+    #[serde(default)]
+    code_challenge_method: Option<String>,
+    approve: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AuthorizeResponse {
+    success: bool,
+    message: String,
+    // Where to send the user next, with either a code or an error for the
+    // client
+    redirect_to: Option<String>,
+}
+
+impl AuthorizeResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(AuthorizeResponse {
+            success: false,
+            message: message.to_string(),
+            redirect_to: None,
+        })
+    }
+}
+
+const SUPPORTED_SCOPES: &[&str] = &["openid", "profile"];
+
+// Called by the web frontend once the user has approved or denied a client
+#[post("/oauth/authorize", data = "<body>")]
+fn authorize(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<AuthorizeRequest>,
+) -> Json<AuthorizeResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user,
+        Err(err) => return AuthorizeResponse::failure(&err.to_string()),
+    };
+
+    // Until the redirect URI is known to be the client's, errors mustn't
+    // send the user there (RFC 6749 section 4.1.2.1)
+    let client = match db.get_oauth_client(&body.client_id) {
+        Ok(client) => client,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return AuthorizeResponse::failure("Unknown client"),
+        Err(err) => {
+            log::error!("Error looking up OAuth client {}: {:?}", body.client_id, err);
+            return AuthorizeResponse::failure(&format!("Error looking up client: {}", err));
+        }
+    };
+    if !client.allows_redirect_uri(&body.redirect_uri) {
+        return AuthorizeResponse::failure("Redirect URI isn't registered for this client");
+    }
+
+    let state = body.state.as_deref();
+    let error_redirect = |error: &str, description: &str| {
+        let mut params = vec![("error", error), ("error_description", description)];
+        params.extend(state.map(|state| ("state", state)));
+        Json(AuthorizeResponse {
+            success: false,
+            message: description.to_string(),
+            redirect_to: Some(oauth::redirect_with(&body.redirect_uri, &params)),
+        })
+    };
+    if !body.approve {
+        log::info!("User {} denied OAuth client {}", user.account().username(), client.client_id());
+        return error_redirect("access_denied", "The user denied the request");
+    }
+    if body.response_type != "code" {
+        return error_redirect("unsupported_response_type", "Only the code response type is supported");
+    }
+    let Some(code_challenge) = &body.code_challenge else {
+        return error_redirect("invalid_request", "PKCE is required");
+    };
+    if body.code_challenge_method.as_deref() != Some("S256") {
+        return error_redirect("invalid_request", "Only the S256 code challenge method is supported");
+    }
+    let scopes: Vec<&str> = body.scope.split_whitespace().collect();
+    if scopes.iter().any(|scope| !SUPPORTED_SCOPES.contains(scope)) {
+        return error_redirect("invalid_scope", "Unsupported scope");
+    }
+
+    // Handles from device keys and device grants don't know when the user
+    // last entered their password, so ID tokens for them leave it out
+    let authenticated_at = user.handle().expect("API keys were turned away").authenticated_at();
+    let grant = AuthorizationGrant {
+        client_id: client.client_id().to_string(),
+        user_id: user.account().id(),
+        redirect_uri: body.redirect_uri.clone(),
+        scope: scopes.join(" "),
+        nonce: body.nonce.clone(),
+        code_challenge: code_challenge.clone(),
+        auth_time: (authenticated_at != DateTime::UNIX_EPOCH).then_some(authenticated_at),
+    };
+    match db.add_authorization_code(&grant) {
+        Ok(code) => {
+            log::info!("User {} approved OAuth client {}", user.account().username(), client.client_id());
+            let mut params = vec![("code", code.as_str())];
+            params.extend(state.map(|state| ("state", state)));
+            Json(AuthorizeResponse {
+                success: true,
+                message: "".to_string(),
+                redirect_to: Some(oauth::redirect_with(&body.redirect_uri, &params)),
+            })
+        },
+        Err(err) => {
+            log::error!("Failed to create authorization code for user {}: {:?}", user.account().username(), err);
+            error_redirect("server_error", "Failed to create authorization code")
+        },
+    }
+}
+
+/// Standard claims about the bearer handle's user (OpenID Connect Core
+/// section 5.3)
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UserInfoResponse {
+    sub: String,
+    preferred_username: String,
+    premium: bool,
+}
+
+#[get("/oauth/userinfo")]
+fn userinfo(auth: Result<AuthenticatedUser, AuthError>) -> OAuthResult<UserInfoResponse> {
+    let user = match auth.and_then(|user| user.require_scope(Scope::ProfileRead)) {
+        Ok(user) => user,
+        // RFC 6750 section 3.1
+        Err(AuthError::InsufficientScope(_)) => return oauth_error(Status::Forbidden, "insufficient_scope"),
+        Err(_) => return oauth_error(Status::Unauthorized, "invalid_token"),
+    };
+    let user = user.account();
+    Ok(Json(UserInfoResponse {
+        sub: user.id().to_string(),
+        preferred_username: user.username().to_string(),
+        premium: user.premium(),
+    }))
+}
+
+/// OpenID Provider Metadata (OpenID Connect Discovery section 3)
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OpenIdConfiguration {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    jwks_uri: String,
+    device_authorization_endpoint: String,
+    response_types_supported: Vec<String>,
+    grant_types_supported: Vec<String>,
+    subject_types_supported: Vec<String>,
+    id_token_signing_alg_values_supported: Vec<String>,
+    scopes_supported: Vec<String>,
+    token_endpoint_auth_methods_supported: Vec<String>,
+    code_challenge_methods_supported: Vec<String>,
+    claims_supported: Vec<String>,
+}
+
+#[get("/.well-known/openid-configuration")]
+fn openid_configuration(db: Database) -> Json<OpenIdConfiguration> {
+    let issuer = db.oidc_config().issuer.trim_end_matches('/');
+    let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+    Json(OpenIdConfiguration {
+        issuer: issuer.to_string(),
+        authorization_endpoint: format!("{}/oauth/authorize", issuer),
+        token_endpoint: format!("{}/oauth/token", issuer),
+        userinfo_endpoint: format!("{}/oauth/userinfo", issuer),
+        jwks_uri: format!("{}/.well-known/jwks.json", issuer),
+        device_authorization_endpoint: format!("{}/oauth/device_authorization", issuer),
+        response_types_supported: strings(&["code"]),
+        grant_types_supported: strings(&[oauth::AUTHORIZATION_CODE_GRANT, oauth::DEVICE_CODE_GRANT]),
+        subject_types_supported: strings(&["public"]),
+        id_token_signing_alg_values_supported: strings(&["EdDSA"]),
+        scopes_supported: strings(SUPPORTED_SCOPES),
+        token_endpoint_auth_methods_supported: strings(&["client_secret_post", "none"]),
+        code_challenge_methods_supported: strings(&["S256"]),
+        claims_supported: strings(&[
+            "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce", "preferred_username", "premium",
+        ]),
+    })
+}
+
+#[get("/.well-known/jwks.json")]
+fn jwks(db: Database) -> Result<Json<JwkSet>, Status> {
+    match db.get_public_keys() {
+        Ok(keys) => Ok(Json(JwkSet { keys })),
+        Err(err) => {
+            log::error!("Failed to get signing keys: {:?}", err);
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+/// Form-encoded, as OAuth requires. The client may authenticate with HTTP
+/// Basic instead of `client_id` and `client_secret`.
+#[derive(FromForm)]
+pub struct IntrospectRequest {
+    token: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// RFC 7662 section 2.2. Only `active` is sent for tokens that aren't.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct IntrospectResponse {
+    active: bool,
+    // Only API keys and handles issued to OAuth clients are limited to scopes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<UserID>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    premium: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+}
+
+// Lets a service that was handed a handle or API key ask whether it's valid
+// and whose it is (RFC 7662). Services authenticate as a confidential OAuth
+// client that is configured as a resource server.
+#[post("/introspect", data = "<body>")]
+fn introspect(
+    db: Database,
+    basic: Result<ClientCredentials, AuthError>,
+    body: Form<IntrospectRequest>,
+) -> OAuthResult<IntrospectResponse> {
+    let credentials = match basic {
+        Ok(credentials) => Some((credentials.client_id, credentials.client_secret)),
+        Err(AuthError::Missing) => body.client_id.clone().zip(body.client_secret.clone()),
+        Err(_) => None,
+    };
+    let Some((client_id, client_secret)) = credentials else {
+        return oauth_error(Status::Unauthorized, "invalid_client");
+    };
+    if !db.oidc_config().is_resource_server(&client_id) {
+        log::warn!("Introspection attempted by client that isn't a resource server: {}", client_id);
+        return oauth_error(Status::Unauthorized, "invalid_client");
+    }
+    match db.check_client_secret(&client_id, &client_secret) {
+        Ok(true) => {},
+        Ok(false) => {
+            log::warn!("Invalid credentials for introspection from client: {}", client_id);
+            return oauth_error(Status::Unauthorized, "invalid_client");
+        },
+        Err(err) => {
+            log::error!("Error checking secret of OAuth client {}: {:?}", client_id, err);
+            return oauth_error(Status::InternalServerError, "server_error");
+        },
+    }
+    let Some(token) = &body.token else {
+        return oauth_error(Status::BadRequest, "invalid_request");
+    };
+
+    // Anything that isn't a handle or API key is just not an active one.
+    // Looking them up doesn't count as using them, so handles still go idle.
+    let token = token.trim();
+    let found = if api_key::is_token(token) {
+        db.get_api_key(token).and_then(|(key, user_id)| {
+            let scope = scope::format_scopes(key.scopes());
+            let expires_at = key.expires_at();
+            Ok((db.get_user_by_id(user_id)?, Some(scope), key.created_at(), expires_at))
+        })
+    } else {
+        HandleToken::parse(token)
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
+            .and_then(|token| Handle::find(&token, &db))
+            .and_then(|handle| {
+                let idle_expiry = handle.last_used_at() + db.session_config().idle_ttl();
+                let expires_at = handle.expires_at().min(idle_expiry);
+                let scope = handle.scopes().map(scope::format_scopes);
+                Ok((handle.owner(&db)?, scope, handle.created_at(), Some(expires_at)))
+            })
+    };
+    let (user, scope, created_at, expires_at) = match found {
+        Ok(found) => found,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(Json(IntrospectResponse::default())),
+        Err(err) => {
+            log::error!("Error introspecting token for client {}: {:?}", client_id, err);
+            return oauth_error(Status::InternalServerError, "server_error");
+        },
+    };
+    Ok(Json(IntrospectResponse {
+        active: true,
+        scope,
+        token_type: Some("Bearer".to_string()),
+        sub: Some(user.id().to_string()),
+        user_id: Some(user.id()),
+        username: Some(user.username().to_string()),
+        premium: Some(user.premium()),
+        iat: Some(created_at.timestamp()),
+        exp: expires_at.map(|expires_at| expires_at.timestamp()),
+    }))
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OAuthClientsResponse {
+    success: bool,
+    message: String,
+    clients: Option<Vec<OAuthClient>>,
+}
+
+#[get("/user/oauth/clients")]
+fn get_oauth_clients(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<OAuthClientsResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(OAuthClientsResponse {
+                success: false,
+                message: err.to_string(),
+                clients: None,
+            });
+        }
+    };
+
+    match db.get_oauth_clients(user.id()) {
+        Ok(clients) => Json(OAuthClientsResponse {
+            success: true,
+            message: "".to_string(),
+            clients: Some(clients),
+        }),
+        Err(err) => {
+            log::error!("Error retrieving OAuth clients for user {}: {:?}", user.username(), err);
+            Json(OAuthClientsResponse {
+                success: false,
+                message: format!("Error retrieving clients: {}", err),
+                clients: None,
+            })
+        },
+    }
+}
+
+const MAX_CLIENT_NAME_LEN: usize = 64;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OAuthClientRegisterRequest {
+    name: String,
+    redirect_uris: Vec<String>,
+    // Whether the client can keep a secret, i.e. runs on a server
+    confidential: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OAuthClientRegisterResponse {
+    success: bool,
+    message: String,
+    client_id: Option<String>,
+    // Only for confidential clients, and only shown here
+    client_secret: Option<String>,
+}
+
+impl OAuthClientRegisterResponse {
+    fn failure(message: &str) -> Json<Self> {
+        Json(OAuthClientRegisterResponse {
+            success: false,
+            message: message.to_string(),
+            client_id: None,
+            client_secret: None,
+        })
+    }
+}
+
+#[post("/user/oauth/clients/register", data = "<body>")]
+fn register_oauth_client(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    body: Json<OAuthClientRegisterRequest>,
+) -> Json<OAuthClientRegisterResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => return OAuthClientRegisterResponse::failure(&err.to_string()),
+    };
+
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_CLIENT_NAME_LEN {
+        return OAuthClientRegisterResponse::failure(&format!(
+            "Client names must be 1 to {} characters",
+            MAX_CLIENT_NAME_LEN
+        ));
+    }
+    if body.redirect_uris.is_empty() || !body.redirect_uris.iter().all(|uri| oauth::is_valid_redirect_uri(uri)) {
+        return OAuthClientRegisterResponse::failure("Redirect URIs must be absolute URIs without a fragment");
+    }
+
+    match db.add_oauth_client(user.id(), name, &body.redirect_uris, body.confidential) {
+        Ok((client, client_secret)) => {
+            log::info!("Registered OAuth client {} ({}) for user: {}", client.client_id(), client.name(), user.username());
+            Json(OAuthClientRegisterResponse {
+                success: true,
+                message: "".to_string(),
+                client_id: Some(client.client_id().to_string()),
+                client_secret,
+            })
+        },
+        Err(err) => {
+            log::error!("Error registering OAuth client for user {}: {:?}", user.username(), err);
+            OAuthClientRegisterResponse::failure(&format!("Error registering client: {}", err))
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OAuthClientDeleteResponse {
+    success: bool,
+    message: String,
+}
+
+#[post("/user/oauth/clients/<client_id>/delete")]
+fn delete_oauth_client(
+    db: Database,
+    auth: Result<AuthenticatedUser, AuthError>,
+    client_id: &str,
+) -> Json<OAuthClientDeleteResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(OAuthClientDeleteResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.delete_oauth_client(user.id(), client_id) {
+        Ok(true) => {
+            log::info!("Deleted OAuth client {} for user: {}", client_id, user.username());
+            Json(OAuthClientDeleteResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(OAuthClientDeleteResponse {
+            success: false,
+            message: "Client not found".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error deleting OAuth client {} for user {}: {:?}", client_id, user.username(), err);
+            Json(OAuthClientDeleteResponse {
+                success: false,
+                message: format!("Error deleting client: {}", err),
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LogoutResponse {
+    success: bool,
+    message: String,
+    // Number of handles that were revoked
+    revoked: usize,
+}
+
+#[post("/user/logout")]
+fn logout(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<LogoutResponse> {
+    // Any handle can revoke itself, even one limited to scopes. API keys are
+    // deleted at `/user/api-keys/<id>/delete` instead.
+    let auth = auth.and_then(|user| match user.handle() {
+        Some(_) => Ok(user),
+        None => Err(AuthError::HandleRequired),
+    });
+    let user = match auth {
+        Ok(user) => user,
+        Err(err) => {
+            return Json(LogoutResponse {
+                success: false,
+                message: err.to_string(),
+                revoked: 0,
+            });
+        }
+    };
+
+    let username = user.account().username();
+    let handle = user.handle().expect("API keys were turned away");
+    match handle.delete(user.account().id(), &db) {
+        Ok(deleted) => {
+            log::info!("User {} logged out of handle #{}", username, handle.id());
+            Json(LogoutResponse {
+                success: true,
+                message: "".to_string(),
+                revoked: deleted as usize,
+            })
+        },
+        Err(err) => {
+            log::error!("Error logging out user {}: {:?}", username, err);
+            Json(LogoutResponse {
+                success: false,
+                message: format!("Error revoking handle: {}", err),
+                revoked: 0,
+            })
+        },
+    }
+}
+
+// Needs no recent authentication, unlike revoking a single session: it
+// revokes the handle it's sent with too, so a stolen handle can't use it to
+// lock the owner out while keeping access, and it's what a user reaches for
+// when they think one was stolen
+#[post("/user/logout-all")]
+fn logout_all(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<LogoutResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(LogoutResponse {
+                success: false,
+                message: err.to_string(),
+                revoked: 0,
+            });
+        }
+    };
+
+    let revoked = Handle::get_all_for_user(user.id(), &db).and_then(|handles| {
+        let mut revoked = 0;
+        for handle in handles {
+            if handle.delete(user.id(), &db)? {
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    });
+    match revoked {
+        Ok(revoked) => {
+            log::info!("User {} logged out everywhere, revoking {} handles", user.username(), revoked);
+            Json(LogoutResponse {
+                success: true,
+                message: "".to_string(),
+                revoked,
+            })
+        },
+        Err(err) => {
+            log::error!("Error logging out user {} everywhere: {:?}", user.username(), err);
+            Json(LogoutResponse {
+                success: false,
+                message: format!("Error revoking handles: {}", err),
+                revoked: 0,
+            })
+        },
+    }
+}
+
+/// A handle as shown to its owner. The token itself is never shown again.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    id: HandleID,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    device_name: Option<String>,
+    // Only for handles issued to OAuth clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scopes: Option<Vec<Scope>>,
+    // Whether this is the handle the request was sent with
+    current: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SessionsResponse {
+    success: bool,
+    message: String,
+    sessions: Option<Vec<Session>>,
+}
+
+#[get("/user/sessions")]
+fn get_sessions(db: Database, auth: Result<AuthenticatedUser, AuthError>) -> Json<SessionsResponse> {
+    let user = match auth.and_then(AuthenticatedUser::require_handle) {
+        Ok(user) => user,
+        Err(err) => {
+            return Json(SessionsResponse {
+                success: false,
+                message: err.to_string(),
+                sessions: None,
+            });
+        }
+    };
+    let current = user.handle().expect("API keys were turned away").id();
+    let user = user.into_account();
+
+    match Handle::get_all_for_user(user.id(), &db) {
+        Ok(handles) => {
+            let idle_ttl = db.session_config().idle_ttl();
+            let sessions = handles
+                .into_iter()
+                .map(|handle| {
+                    let client = handle.client().clone();
+                    Session {
+                        id: handle.id(),
+                        created_at: handle.created_at(),
+                        last_used_at: handle.last_used_at(),
+                        expires_at: handle.expires_at().min(handle.last_used_at() + idle_ttl),
+                        user_agent: client.user_agent,
+                        ip_address: client.ip_address,
+                        device_name: client.device_name,
+                        scopes: handle.scopes().map(<[Scope]>::to_vec),
+                        current: handle.id() == current,
+                    }
+                })
+                .collect();
+            Json(SessionsResponse {
+                success: true,
+                message: "".to_string(),
+                sessions: Some(sessions),
+            })
+        },
+        Err(err) => {
+            log::error!("Error retrieving sessions for user {}: {:?}", user.username(), err);
+            Json(SessionsResponse {
+                success: false,
+                message: format!("Error retrieving sessions: {}", err),
+                sessions: None,
+            })
+        },
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SessionDeleteResponse {
+    success: bool,
+    message: String,
+}
+
+// Revokes one of the user's handles by its ID, since only the client that
+// holds a handle knows its token
+#[post("/user/sessions/<id>/delete")]
+fn delete_session(
+    db: Database,
+    auth: Result<RecentlyAuthenticatedUser, AuthError>,
+    id: HandleID,
+) -> Json<SessionDeleteResponse> {
+    let user = match auth {
+        Ok(user) => user.into_account(),
+        Err(err) => {
+            return Json(SessionDeleteResponse {
+                success: false,
+                message: err.to_string(),
+            });
+        }
+    };
+
+    match db.delete_handle(id, user.id()) {
+        Ok(true) => {
+            log::info!("User {} revoked handle #{}", user.username(), id);
+            Json(SessionDeleteResponse {
+                success: true,
+                message: "".to_string(),
+            })
+        },
+        Ok(false) => Json(SessionDeleteResponse {
+            success: false,
+            message: "Session not found".to_string(),
+        }),
+        Err(err) => {
+            log::error!("Error revoking handle #{} for user {}: {:?}", id, user.username(), err);
+            Json(SessionDeleteResponse {
+                success: false,
+                message: format!("Error revoking session: {}", err),
+            })
+        },
+    }
+}
+
+#[get("/metrics")]
+fn get_metrics() -> String {
+    metrics::render()
+}
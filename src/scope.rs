@@ -0,0 +1,62 @@
+use std::fmt::Display;
+
+/// What an API key, or a handle issued to an OAuth client or created with an
+/// API key, may be used for. Other handles can do everything; these can only
+/// call the routes their scopes cover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Scope {
+    /// Read the user's profile, as `/oauth/userinfo` returns it
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+    /// Create handles with `/user/handle/create`
+    #[serde(rename = "handles:write")]
+    HandlesWrite,
+}
+
+impl Scope {
+    pub const ALL: &'static [Scope] = &[Scope::ProfileRead, Scope::HandlesWrite];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ProfileRead => "profile:read",
+            Scope::HandlesWrite => "handles:write",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|s| s.as_str() == scope)
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Scopes are stored space-separated, as OAuth sends them
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" ")
+}
+
+/// Unknown scopes are dropped; they can only have come from an older version
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split_whitespace().filter_map(Scope::parse).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_round_trip() {
+        assert_eq!(parse_scopes(&format_scopes(Scope::ALL)), Scope::ALL);
+        assert_eq!(format_scopes(&[Scope::ProfileRead]), "profile:read");
+    }
+
+    #[test]
+    fn unknown_scopes_are_dropped() {
+        assert_eq!(parse_scopes("admin  handles:write"), vec![Scope::HandlesWrite]);
+        assert_eq!(Scope::parse("profile"), None);
+    }
+}
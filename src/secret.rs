@@ -0,0 +1,90 @@
+use std::{fs, io::Write, path::Path, sync::OnceLock};
+
+use rand::RngCore;
+use ring::{aead, hmac};
+
+use crate::config::Config;
+
+const SECRET_LEN: usize = 32;
+
+/// The server's secret, kept outside the database at `secret_key_path` and
+/// generated on first start. It keys the hashes of stored tokens, so a copy
+/// of the database alone isn't enough to use or brute-force them.
+///
+/// Loaded once per process; the path can't change while running.
+pub fn server_secret(config: &Config) -> &'static [u8] {
+    static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+    SECRET.get_or_init(|| load_or_generate(Path::new(&config.secret_key_path)))
+}
+
+/// A key for one purpose, derived from the server secret so that keys for
+/// different purposes are independent of each other
+pub fn derive_key(config: &Config, purpose: &str) -> hmac::Key {
+    let root = hmac::Key::new(hmac::HMAC_SHA256, server_secret(config));
+    let derived = hmac::sign(&root, purpose.as_bytes());
+    hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref())
+}
+
+/// A key for encrypting data that has to be read back, such as TOTP
+/// secrets, derived the same way as `derive_key`
+pub fn derive_sealing_key(config: &Config, purpose: &str) -> aead::LessSafeKey {
+    let root = hmac::Key::new(hmac::HMAC_SHA256, server_secret(config));
+    let derived = hmac::sign(&root, purpose.as_bytes());
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, derived.as_ref())
+        .expect("HMAC-SHA256 output is a valid AES-256 key");
+    aead::LessSafeKey::new(key)
+}
+
+/// Encrypts `plaintext` under a random nonce, which is prepended to the
+/// result. `aad` isn't stored but has to match when opening, which ties the
+/// ciphertext to e.g. the row it belongs to.
+pub fn seal(key: &aead::LessSafeKey, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let mut sealed = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::from(aad),
+        &mut sealed,
+    )
+    .expect("plaintext fits in a single AES-GCM message");
+    [nonce.as_slice(), &sealed].concat()
+}
+
+/// Reverses `seal`. Returns `None` if the data was tampered with or sealed
+/// under a different key or `aad`.
+pub fn open(key: &aead::LessSafeKey, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < aead::NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+    let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut buf = ciphertext.to_vec();
+    let plaintext = key.open_in_place(nonce, aead::Aad::from(aad), &mut buf).ok()?;
+    Some(plaintext.to_vec())
+}
+
+fn load_or_generate(path: &Path) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(secret) if secret.len() >= SECRET_LEN => return secret,
+        Ok(_) => panic!("Secret key at {} is too short", path.display()),
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            panic!("Failed to read secret key at {}: {}", path.display(), e)
+        }
+        Err(_) => {}
+    }
+
+    log::warn!("No secret key at {}, generating a new one", path.display());
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&secret))
+        .unwrap_or_else(|e| panic!("Failed to write secret key to {}: {}", path.display(), e));
+    secret
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+
+use crate::{config::Config, database::Database, metrics};
+
+/// Periodically deletes expired handles and handles whose user no longer
+/// exists, so the `handle` table doesn't grow without bound.
+pub fn handle_purge() -> AdHoc {
+    AdHoc::on_liftoff("Handle purge", |rocket| {
+        Box::pin(async move {
+            let config = rocket.state::<Config>().cloned().unwrap_or_default();
+            let period = Duration::from_secs(config.session.purge_interval.max(1));
+            rocket::tokio::spawn(async move {
+                let mut interval = rocket::tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    let config = config.clone();
+                    let res = rocket::tokio::task::spawn_blocking(move || {
+                        Database::with_config(config).purge_handles()
+                    })
+                    .await;
+                    match res {
+                        Ok(Ok(stats)) => {
+                            metrics::HANDLE_PURGE_RUNS.add(1);
+                            metrics::HANDLES_PURGED_EXPIRED.add(stats.expired as u64);
+                            metrics::HANDLES_PURGED_ORPHANED.add(stats.orphaned as u64);
+                            log::info!(
+                                "Purged {} expired and {} orphaned handles",
+                                stats.expired,
+                                stats.orphaned
+                            );
+                        }
+                        Ok(Err(e)) => log::error!("Failed to purge handles: {}", e),
+                        Err(e) => log::error!("Handle purge task panicked: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
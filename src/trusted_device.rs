@@ -0,0 +1,159 @@
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use ring::hmac;
+
+use crate::account::UserID;
+
+/// Row ID of a trusted device
+pub type TrustedDeviceID = i64;
+
+const TOKEN_PREFIX: &str = "abltd_";
+const TAG_LEN: usize = 32;
+const SECRET_LEN: usize = 16;
+const PAYLOAD_LEN: usize = 16 + SECRET_LEN + TAG_LEN;
+
+/// Random per-device value carried in the token. Only a keyed hash of it is
+/// stored, so a token is tied to the row it was issued for, even if SQLite
+/// later hands the same ID to another one.
+pub type TrustedDeviceSecret = [u8; SECRET_LEN];
+
+/// A device a user chose to trust after logging in with a second factor.
+/// Until it expires or is revoked, logging in from it only needs the
+/// password.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TrustedDevice {
+    id: TrustedDeviceID,
+    name: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl TrustedDevice {
+    pub fn from_db(
+        id: TrustedDeviceID,
+        name: Option<String>,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        last_used_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        TrustedDevice {
+            id,
+            name,
+            created_at,
+            expires_at,
+            last_used_at,
+        }
+    }
+
+    pub fn id(&self) -> TrustedDeviceID {
+        self.id
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// The token the device presents at `/user/auth`: the device's ID,
+    /// expiry and secret, signed together with the user's ID so it can't be
+    /// moved to another account or extended. The database row still has to
+    /// exist with the same secret, so revoking it invalidates the token.
+    pub fn token(&self, user_id: UserID, secret: &TrustedDeviceSecret, key: &hmac::Key) -> String {
+        let expires = self.expires_at.timestamp();
+        let tag = hmac::sign(key, &signed_message(user_id, self.id, expires, secret));
+        let mut payload = Vec::with_capacity(PAYLOAD_LEN);
+        payload.extend_from_slice(&self.id.to_be_bytes());
+        payload.extend_from_slice(&expires.to_be_bytes());
+        payload.extend_from_slice(secret);
+        payload.extend_from_slice(tag.as_ref());
+        format!("{}{}", TOKEN_PREFIX, Base64UrlUnpadded::encode_string(&payload))
+    }
+}
+
+pub fn generate_secret() -> TrustedDeviceSecret {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Checks a token's signature and expiry, returning the ID of the device it
+/// was issued to and the secret to match against its row
+pub fn verify_token(
+    token: &str,
+    user_id: UserID,
+    key: &hmac::Key,
+) -> Option<(TrustedDeviceID, TrustedDeviceSecret)> {
+    let payload = Base64UrlUnpadded::decode_vec(token.strip_prefix(TOKEN_PREFIX)?).ok()?;
+    if payload.len() != PAYLOAD_LEN {
+        return None;
+    }
+    let (id, rest) = payload.split_at(8);
+    let (expires, rest) = rest.split_at(8);
+    let (secret, tag) = rest.split_at(SECRET_LEN);
+    let id = TrustedDeviceID::from_be_bytes(id.try_into().ok()?);
+    let expires = i64::from_be_bytes(expires.try_into().ok()?);
+    let secret: TrustedDeviceSecret = secret.try_into().ok()?;
+    hmac::verify(key, &signed_message(user_id, id, expires, &secret), tag).ok()?;
+    (expires > Utc::now().timestamp()).then_some((id, secret))
+}
+
+fn signed_message(user_id: UserID, id: TrustedDeviceID, expires: i64, secret: &TrustedDeviceSecret) -> Vec<u8> {
+    let mut message = format!("{}:{}:{}:", user_id, id, expires).into_bytes();
+    message.extend_from_slice(secret);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use ring::rand::SystemRandom;
+
+    use super::*;
+
+    fn key() -> hmac::Key {
+        hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).unwrap()
+    }
+
+    fn device(id: TrustedDeviceID, expires_in: Duration) -> TrustedDevice {
+        let now = Utc::now();
+        TrustedDevice::from_db(id, None, now, now + expires_in, None)
+    }
+
+    #[test]
+    fn tokens_verify() {
+        let key = key();
+        let secret = generate_secret();
+        let token = device(7, Duration::days(1)).token(1, &secret, &key);
+        assert_eq!(verify_token(&token, 1, &key), Some((7, secret)));
+    }
+
+    #[test]
+    fn tokens_are_bound_to_the_user_and_key() {
+        let key = key();
+        let token = device(7, Duration::days(1)).token(1, &generate_secret(), &key);
+        assert_eq!(verify_token(&token, 2, &key), None);
+        assert_eq!(verify_token(&token, 1, &self::key()), None);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let key = key();
+        let token = device(7, Duration::seconds(-1)).token(1, &generate_secret(), &key);
+        assert_eq!(verify_token(&token, 1, &key), None);
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let key = key();
+        let token = device(7, Duration::days(1)).token(1, &generate_secret(), &key);
+        let mut payload = Base64UrlUnpadded::decode_vec(token.strip_prefix(TOKEN_PREFIX).unwrap()).unwrap();
+        // Move the token to another device
+        payload[7] ^= 1;
+        let tampered = format!("{}{}", TOKEN_PREFIX, Base64UrlUnpadded::encode_string(&payload));
+        assert_eq!(verify_token(&tampered, 1, &key), None);
+
+        assert_eq!(verify_token(&token[..token.len() - 2], 1, &key), None);
+        assert_eq!(verify_token(token.strip_prefix(TOKEN_PREFIX).unwrap(), 1, &key), None);
+    }
+}